@@ -0,0 +1,57 @@
+//! Audit log of changes made through the mount.
+//!
+//! With an `[audit]` table configured, every create, write, truncate, rename, unlink and other attribute
+//! change is recorded along with the uid, gid and pid of the process which made it. Writes are recorded
+//! once per file when they are committed (on release or fsync) with the number of bytes written. For
+//! tables with `write_transactions` the record is written in the same transaction as the change, and a
+//! change which can't be recorded is rolled back.
+//!
+//! The audit table needs these columns:
+//! ```sql
+//! create table pgfs_audit (
+//!     id serial primary key,
+//!     operation text not null,
+//!     uid int,
+//!     gid int,
+//!     pid int,
+//!     table_name text,
+//!     row_id int,
+//!     file_name text,
+//!     bytes bigint,
+//!     at timestamptz not null default now()
+//! );
+//! ```
+
+use crate::statements::Statements;
+use fuser::Request;
+use postgres::Client;
+
+/// The process making a change
+#[derive(Clone, Copy, Debug)]
+pub struct Caller {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+}
+
+impl Caller {
+    pub fn of(req: &Request<'_>) -> Caller {
+        Caller { uid: req.uid(), gid: req.gid(), pid: req.pid() }
+    }
+}
+
+/// The file changed
+#[derive(Debug)]
+pub struct Target {
+    pub table: String,
+    pub row_id: i32,
+    pub name: String,
+}
+
+pub fn record(client: &mut Client, statements: &mut Statements, audit_table: &str, operation: &str, caller: &Caller, target: &Target, bytes: Option<i64>) -> Result<(), postgres::Error> {
+    let query = format!("insert into {} (operation, uid, gid, pid, table_name, row_id, file_name, bytes) \
+        values ($1, $2, $3, $4, $5, $6, $7, $8)", audit_table);
+    statements.execute(client, query.as_str(), &[&operation, &(caller.uid as i32), &(caller.gid as i32), &(caller.pid as i32),
+        &target.table, &target.row_id, &target.name, &bytes])?;
+    Ok(())
+}
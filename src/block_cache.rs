@@ -0,0 +1,270 @@
+//! Cache of blocks read from files.
+//!
+//! Without a cache each `read` runs its own `substring()` query, and as PostgreSQL has to detoast the whole
+//! value to take a substring of it, reading a large file a few KiB at a time is slow. Instead, reads are
+//! made in blocks which are kept in an LRU cache keyed by inode and block number. A read which misses the
+//! cache fetches the blocks it needs plus a readahead window after them in one query, and files no bigger
+//! than `whole_file` are fetched in one go.
+//!
+//! Blocks of a file are dropped when it is written, truncated or committed through the mount, when the
+//! change feed reports a change to it, and (without the change feed, which is the only way to hear about
+//! changes made by other applications) whenever it is opened. Reads by a handle writing in a transaction
+//! and reads when roles are mapped (as different roles may see different data) skip the cache.
+//!
+//! ```toml
+//! [read_cache]
+//! size = "64M"
+//! block_size = "128K"
+//! readahead = "1M"
+//! whole_file = "256K"
+//! ```
+//!
+//! A `size` of 0 turns the cache off.
+//!
+//! The cache is shared with the async backend's tasks, which fill it from reads made off the FUSE thread.
+//! Each invalidation of a file moves it to a new generation, and blocks fetched for an earlier one are
+//! dropped rather than cached.
+
+use crate::Inode;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    ///most bytes to keep
+    pub size: u64,
+    pub block_size: u64,
+    ///bytes to read past the end of a read which misses the cache
+    pub readahead: u64,
+    ///files up to this size are read whole
+    pub whole_file: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig { size: 64 << 20, block_size: 128 << 10, readahead: 1 << 20, whole_file: 256 << 10 }
+    }
+}
+
+pub type BlockKey = (Inode, u64);
+
+#[derive(Clone)]
+pub struct BlockCache {
+    config: CacheConfig,
+    blocks: Arc<Mutex<Blocks>>,
+}
+
+#[derive(Default)]
+struct Blocks {
+    ///block data, and when it was last used
+    blocks: HashMap<BlockKey, (Vec<u8>, u64)>,
+    ///blocks by when they were last used, oldest first
+    by_use: BTreeMap<u64, BlockKey>,
+    clock: u64,
+    bytes: u64,
+    ///how many times each file has been invalidated
+    generations: HashMap<Inode, u64>,
+}
+
+impl BlockCache {
+    pub fn new(config: CacheConfig) -> BlockCache {
+        BlockCache { config, blocks: Arc::new(Mutex::new(Blocks::default())) }
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.size > 0
+    }
+
+    /// Read `size` bytes at `offset` from a file of `file_size` bytes, fetching any blocks which aren't
+    /// cached with `fetch(offset, length)`, which returns the bytes there (fewer at the end of the file)
+    pub fn read<E>(&self, ino: Inode, file_size: u64, offset: u64, size: u32,
+                   fetch: impl FnOnce(u64, u64) -> Result<Vec<u8>, E>) -> Result<Vec<u8>, E> {
+        let end = file_size.min(offset + size as u64);
+        if offset >= end {
+            return Ok(vec![]);
+        }
+        let block_size = self.config.block_size;
+        let mut blocks = self.blocks.lock().unwrap();
+        let (first, last) = (offset / block_size, (end - 1) / block_size);
+        //kept until the read is answered, in case caching it pushed out blocks it needs
+        let mut fetched = None;
+        if let Some(missing) = (first..=last).find(|block| !blocks.blocks.contains_key(&(ino, *block))) {
+            let (fetch_start, fetch_end) = if file_size <= self.config.whole_file {
+                (0, file_size)
+            } else {
+                let readahead_end = (last + 1) * block_size + self.config.readahead;
+                (missing * block_size, file_size.min(readahead_end.div_ceil(block_size) * block_size))
+            };
+            let data = fetch(fetch_start, fetch_end - fetch_start)?;
+            for (i, chunk) in data.chunks(block_size as usize).enumerate() {
+                blocks.insert(&self.config, (ino, fetch_start / block_size + i as u64), chunk.to_vec());
+            }
+            fetched = Some((fetch_start, data));
+        }
+        let mut result = Vec::with_capacity((end - offset) as usize);
+        let mut position = offset;
+        while position < end {
+            let block_start = position / block_size * block_size;
+            let bytes: &[u8] = match blocks.touch((ino, position / block_size)) {
+                Some(data) => data,
+                None => match &fetched {
+                    Some((fetch_start, data)) if block_start >= *fetch_start => {
+                        let from = ((block_start - fetch_start) as usize).min(data.len());
+                        &data[from..(from + block_size as usize).min(data.len())]
+                    }
+                    _ => &[],
+                },
+            };
+            let from = (position - block_start) as usize;
+            let to = ((end - block_start) as usize).min(bytes.len());
+            if from >= to {
+                //the file was shorter than we thought
+                break;
+            }
+            result.extend_from_slice(&bytes[from..to]);
+            position = block_start + to as u64;
+        }
+        Ok(result)
+    }
+
+    /// Drop every block of a file
+    pub fn invalidate(&self, ino: Inode) {
+        let mut blocks = self.blocks.lock().unwrap();
+        *blocks.generations.entry(ino).or_default() += 1;
+        let keys: Vec<BlockKey> = blocks.blocks.keys().filter(|(block_ino, _)| *block_ino == ino).copied().collect();
+        for key in keys {
+            if let Some((data, used)) = blocks.blocks.remove(&key) {
+                blocks.by_use.remove(&used);
+                blocks.bytes -= data.len() as u64;
+            }
+        }
+    }
+
+    /// How many times a file has been invalidated, to tell whether data fetched for it is still current
+    pub fn generation(&self, ino: Inode) -> u64 {
+        self.blocks.lock().unwrap().generations.get(&ino).copied().unwrap_or(0)
+    }
+
+    pub fn contains(&self, key: BlockKey) -> bool {
+        self.blocks.lock().unwrap().blocks.contains_key(&key)
+    }
+
+    /// A copy of a cached block
+    pub fn get(&self, key: BlockKey) -> Option<Vec<u8>> {
+        self.blocks.lock().unwrap().touch(key).map(|data| data.to_vec())
+    }
+
+    /// Cache the bytes of a file from `start`, a block boundary, unless the file has been invalidated
+    /// since `generation`
+    pub fn insert_fetched(&self, ino: Inode, generation: u64, start: u64, data: &[u8]) {
+        let mut blocks = self.blocks.lock().unwrap();
+        if blocks.generations.get(&ino).copied().unwrap_or(0) != generation {
+            return;
+        }
+        let block_size = self.config.block_size;
+        for (i, chunk) in data.chunks(block_size as usize).enumerate() {
+            blocks.insert(&self.config, (ino, start / block_size + i as u64), chunk.to_vec());
+        }
+    }
+}
+
+impl Blocks {
+    fn touch(&mut self, key: BlockKey) -> Option<&[u8]> {
+        self.clock += 1;
+        let (data, used) = self.blocks.get_mut(&key)?;
+        self.by_use.remove(used);
+        *used = self.clock;
+        self.by_use.insert(self.clock, key);
+        Some(data)
+    }
+
+    fn insert(&mut self, config: &CacheConfig, key: BlockKey, data: Vec<u8>) {
+        self.clock += 1;
+        self.bytes += data.len() as u64;
+        if let Some((old, used)) = self.blocks.insert(key, (data, self.clock)) {
+            self.by_use.remove(&used);
+            self.bytes -= old.len() as u64;
+        }
+        self.by_use.insert(self.clock, key);
+        while self.bytes > config.size && let Some((_, oldest)) = self.by_use.pop_first() {
+            if let Some((data, _)) = self.blocks.remove(&oldest) {
+                self.bytes -= data.len() as u64;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(size: u64) -> BlockCache {
+        BlockCache::new(CacheConfig { size, block_size: 4, readahead: 0, whole_file: 0 })
+    }
+
+    /// Read a file whose bytes are their own offsets, counting the fetches made
+    fn read(cache: &BlockCache, ino: Inode, file_size: u64, offset: u64, size: u32, fetches: &mut u32) -> Vec<u8> {
+        cache.read(ino, file_size, offset, size, |start, length| {
+            *fetches += 1;
+            Ok::<_, ()>((start..start + length).map(|byte| byte as u8).collect())
+        }).unwrap()
+    }
+
+    #[test]
+    fn read_fetches_missing_blocks_once() {
+        let cache = cache(1024);
+        let mut fetches = 0;
+        assert_eq!(read(&cache, 2, 10, 2, 5, &mut fetches), vec![2, 3, 4, 5, 6]);
+        assert_eq!(fetches, 1);
+        assert!(cache.contains((2, 0)) && cache.contains((2, 1)) && !cache.contains((2, 2)));
+        assert_eq!(read(&cache, 2, 10, 0, 8, &mut fetches), (0..8).collect::<Vec<u8>>());
+        assert_eq!(fetches, 1);
+        //past the end of the file
+        assert_eq!(read(&cache, 2, 10, 6, 100, &mut fetches), vec![6, 7, 8, 9]);
+        assert_eq!(read(&cache, 2, 10, 10, 4, &mut fetches), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn least_recently_used_blocks_go_first() {
+        let cache = cache(8);
+        cache.insert_fetched(2, 0, 0, &[0; 4]);
+        cache.insert_fetched(2, 0, 4, &[1; 4]);
+        //using block 0 leaves block 1 the oldest
+        assert_eq!(cache.get((2, 0)), Some(vec![0; 4]));
+        cache.insert_fetched(3, 0, 0, &[2; 4]);
+        assert!(cache.contains((2, 0)));
+        assert!(!cache.contains((2, 1)));
+        assert!(cache.contains((3, 0)));
+    }
+
+    #[test]
+    fn replacing_a_block_keeps_the_size_right() {
+        let cache = cache(8);
+        cache.insert_fetched(2, 0, 0, &[0; 4]);
+        cache.insert_fetched(2, 0, 0, &[1; 4]);
+        cache.insert_fetched(2, 0, 4, &[2; 4]);
+        assert_eq!(cache.get((2, 0)), Some(vec![1; 4]));
+        assert!(cache.contains((2, 1)));
+        assert_eq!(cache.blocks.lock().unwrap().bytes, 8);
+    }
+
+    #[test]
+    fn invalidated_files_drop_their_blocks_and_late_fetches() {
+        let cache = cache(1024);
+        cache.insert_fetched(2, 0, 0, &[0; 8]);
+        cache.insert_fetched(3, 0, 0, &[0; 4]);
+        cache.invalidate(2);
+        assert!(!cache.contains((2, 0)) && !cache.contains((2, 1)));
+        assert!(cache.contains((3, 0)));
+        assert_eq!(cache.generation(2), 1);
+        //fetched before the invalidation, so stale
+        cache.insert_fetched(2, 0, 0, &[0; 4]);
+        assert!(!cache.contains((2, 0)));
+        cache.insert_fetched(2, 1, 0, &[0; 4]);
+        assert!(cache.contains((2, 0)));
+    }
+}
@@ -0,0 +1,192 @@
+//! Change feed - keep the filesystem up to date with changes made to the database by other applications.
+//!
+//! Triggers installed with `pgfs install-triggers` send a notification for each row inserted, updated or
+//! deleted in a configured table, with a payload of `<operation> <schema>.<table> <id>`. The table is
+//! matched to a directory by either its schema qualified or its bare name, whichever was configured.
+//! A listener thread receives these and immediately invalidates the kernel's caches for the affected
+//! files and directories, then queues the change so that the filesystem can refresh its own maps before
+//! it next answers a request.
+//!
+//! Notifications caused by pgfs' own connection are ignored, as its maps are already up to date.
+
+use crate::config::TableConfig;
+use crate::{Inode, PgId};
+use fuser::Notifier;
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::{Client, NoTls};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub const DEFAULT_CHANNEL: &str = "pgfs_changes";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChangeOp {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// A change to a single row, as sent by the trigger
+#[derive(Debug)]
+pub struct Change {
+    pub op: ChangeOp,
+    pub table: String,
+    pub id: u64,
+}
+
+impl Change {
+    fn parse(payload: &str) -> Option<Change> {
+        let mut parts = payload.splitn(3, ' ');
+        let op = match parts.next()? {
+            "INSERT" => ChangeOp::Insert,
+            "UPDATE" => ChangeOp::Update,
+            "DELETE" => ChangeOp::Delete,
+            _ => return None,
+        };
+        let table = parts.next()?.to_string();
+        let id = parts.next()?.parse().ok()?;
+        Some(Change { op, table, id })
+    }
+
+    /// The table name without its schema
+    fn bare_table(&self) -> &str {
+        self.table.split_once('.').map_or(self.table.as_str(), |(_, table)| table)
+    }
+}
+
+/// Where a row appears in the filesystem
+#[derive(Clone, Debug)]
+pub struct RowLocation {
+    pub inode: Inode,
+    pub name: String,
+}
+
+/// Location of every row which has been given an inode, shared with the change feed listener so it can
+/// tell the kernel which inodes and names are affected by a change.
+pub type RowIndex = Arc<Mutex<HashMap<PgId, RowLocation>>>;
+
+/// Changes received but not yet applied to the filesystem's maps
+#[derive(Clone, Default)]
+pub struct ChangeFeed {
+    changes: Arc<Mutex<Vec<Change>>>,
+}
+
+impl ChangeFeed {
+    /// Listen for changes on `channel` on a new connection. `table_dirs` maps table names to the inode
+    /// of their directory, and `own_pids` are the backend pids of the filesystem's own connections.
+    pub fn start(&self, connection_string: &str, channel: &str, table_dirs: HashMap<String, Inode>, own_pids: Vec<i32>,
+                 rows: RowIndex, notifier: Notifier) -> Result<(), postgres::Error> {
+        let mut client = Client::connect(connection_string, NoTls)?;
+        client.batch_execute(&format!("LISTEN \"{}\"", channel.replace('"', "\"\"")))?;
+        let changes = self.changes.clone();
+        thread::spawn(move || {
+            let mut notifications = client.notifications();
+            let mut iter = notifications.blocking_iter();
+            loop {
+                let notification = match iter.next() {
+                    Ok(Some(notification)) => notification,
+                    Ok(None) => return,
+                    Err(e) => {
+                        log::error!("Lost the connection listening for changes: {}", e);
+                        return;
+                    }
+                };
+                if own_pids.contains(&notification.process_id()) {
+                    continue;
+                }
+                let Some(mut change) = Change::parse(notification.payload()) else {
+                    log::warn!("Ignoring unrecognised change notification {}", notification.payload());
+                    continue;
+                };
+                let Some((table, dir_inode)) = table_dirs.get_key_value(&change.table)
+                    .or_else(|| table_dirs.get_key_value(change.bare_table())) else {
+                    continue;
+                };
+                //as the directory's table is named in the config
+                change.table = table.clone();
+                invalidate(&notifier, *dir_inode, &change, &rows);
+                changes.lock().unwrap().push(change);
+            }
+        });
+        Ok(())
+    }
+
+    pub fn take_changes(&self) -> Vec<Change> {
+        std::mem::take(&mut *self.changes.lock().unwrap())
+    }
+}
+
+/// Tell the kernel to drop anything it has cached for the changed row. Errors are ignored, as they
+/// usually mean the kernel had nothing cached.
+fn invalidate(notifier: &Notifier, dir_inode: Inode, change: &Change, rows: &RowIndex) {
+    let location = rows.lock().unwrap().get(&PgId { table_inode: dir_inode, pg_id: change.id }).cloned();
+    if let Some(location) = location {
+        match change.op {
+            ChangeOp::Delete => {
+                let _ = notifier.delete(dir_inode, location.inode, OsStr::new(&location.name));
+            }
+            _ => {
+                let _ = notifier.inval_inode(location.inode, 0, 0);
+                let _ = notifier.inval_entry(dir_inode, OsStr::new(&location.name));
+            }
+        }
+    }
+    let _ = notifier.inval_inode(dir_inode, 0, 0);
+}
+
+/// Create a trigger on each table which notifies `channel` of every change
+pub fn install_triggers(client: &mut Client, tables: &[&TableConfig], channel: &str) -> Result<(), postgres::Error> {
+    client.batch_execute(r#"
+create or replace function pgfs_notify_change() returns trigger language plpgsql as $$
+declare
+    row_id text;
+begin
+    if TG_OP = 'DELETE' then
+        row_id := to_jsonb(OLD) ->> TG_ARGV[0];
+    else
+        row_id := to_jsonb(NEW) ->> TG_ARGV[0];
+    end if;
+    perform pg_notify(TG_ARGV[1], TG_OP || ' ' || TG_TABLE_SCHEMA || '.' || TG_TABLE_NAME || ' ' || row_id);
+    return null;
+end
+$$;"#)?;
+    for table in tables {
+        client.batch_execute(&format!(
+            "drop trigger if exists pgfs_notify_change on {table};
+             create trigger pgfs_notify_change after insert or update or delete on {table}
+             for each row execute function pgfs_notify_change('{id}', '{channel}');",
+            table = table.table_name, id = table.id_field.replace('\'', "''"), channel = channel.replace('\'', "''")))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_qualified_table() {
+        let change = Change::parse("UPDATE public.files 42").unwrap();
+        assert_eq!(change.op, ChangeOp::Update);
+        assert_eq!(change.table, "public.files");
+        assert_eq!(change.bare_table(), "files");
+        assert_eq!(change.id, 42);
+    }
+
+    #[test]
+    fn parse_operations() {
+        assert_eq!(Change::parse("INSERT files 1").unwrap().op, ChangeOp::Insert);
+        assert_eq!(Change::parse("DELETE files 1").unwrap().op, ChangeOp::Delete);
+        assert_eq!(Change::parse("DELETE files 1").unwrap().bare_table(), "files");
+    }
+
+    #[test]
+    fn parse_rejects_bad_payloads() {
+        assert!(Change::parse("TRUNCATE public.files 1").is_none());
+        assert!(Change::parse("UPDATE public.files").is_none());
+        assert!(Change::parse("UPDATE public.files abc").is_none());
+        assert!(Change::parse("").is_none());
+    }
+}
@@ -0,0 +1,185 @@
+//! LISTEN/NOTIFY channels exposed as files.
+//!
+//! Each channel is a file in the channels directory. Writing to a channel file sends each line written
+//! as a notification (`pg_notify(channel, line)`) and reading returns payloads, one per line, as they
+//! arrive. A read with nothing to return blocks until something arrives, so `cat` follows the channel,
+//! and `poll` reports the file as readable when payloads are waiting. A read which has waited for
+//! `READ_TIMEOUT` returns nothing (end of file) rather than blocking for ever; `tail -f` carries on
+//! following the channel after that, `cat` stops.
+//!
+//! Notifications are received on a separate connection by a listener thread, which replies to blocked
+//! reads itself so that a waiting reader does not hold up the rest of the filesystem. Only payloads which
+//! arrive while a file is open are returned through it.
+
+use crate::statements::Statements;
+use fuser::{PollHandle, ReplyData, ReplyPoll};
+use libc::{EAGAIN, POLLIN, POLLOUT};
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::{Client, NoTls};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the listener waits for a notification before checking for new channels to listen on
+const LISTEN_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// How long a blocked read waits for a notification before returning nothing
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Receives notifications for all channels and hands them to the open channel files
+pub struct ChannelHub {
+    state: Arc<Mutex<HubState>>,
+}
+
+#[derive(Default)]
+struct HubState {
+    listening: HashSet<String>,
+    to_listen: Vec<String>,
+    subscribers: HashMap<u64, Subscriber>,
+}
+
+/// A channel file open for reading
+struct Subscriber {
+    channel: String,
+    nonblocking: bool,
+    buffer: Vec<u8>,
+    pending_read: Option<(ReplyData, u32, Instant)>,
+    poll_handle: Option<PollHandle>,
+}
+
+impl Subscriber {
+    fn take(&mut self, size: u32) -> Vec<u8> {
+        let end = std::cmp::min(size as usize, self.buffer.len());
+        self.buffer.drain(..end).collect()
+    }
+}
+
+impl ChannelHub {
+    /// Open the listening connection and start the listener thread
+    pub fn start(connection_string: &str, channels: &[String]) -> Result<ChannelHub, postgres::Error> {
+        let client = Client::connect(connection_string, NoTls)?;
+        let state = Arc::new(Mutex::new(HubState {
+            to_listen: channels.to_vec(),
+            ..Default::default()
+        }));
+        let thread_state = state.clone();
+        thread::spawn(move || listen(client, thread_state));
+        Ok(ChannelHub { state })
+    }
+
+    /// Make sure the listener is listening on a channel, e.g. one created through the filesystem
+    pub fn listen(&self, channel: &str) {
+        let mut state = self.state.lock().unwrap();
+        if !state.listening.contains(channel) {
+            state.to_listen.push(channel.to_string());
+        }
+    }
+
+    /// Start collecting payloads for a file handle
+    pub fn subscribe(&self, fh: u64, channel: &str, nonblocking: bool) {
+        self.listen(channel);
+        self.state.lock().unwrap().subscribers.insert(fh, Subscriber {
+            channel: channel.to_string(),
+            nonblocking,
+            buffer: Vec::new(),
+            pending_read: None,
+            poll_handle: None,
+        });
+    }
+
+    pub fn unsubscribe(&self, fh: u64) {
+        if let Some(mut subscriber) = self.state.lock().unwrap().subscribers.remove(&fh)
+            && let Some((reply, _, _)) = subscriber.pending_read.take() {
+            reply.data(&[]);
+        }
+    }
+
+    /// Reply with waiting payloads, or hold on to the reply until some arrive
+    pub fn read(&self, fh: u64, size: u32, reply: ReplyData) {
+        let mut state = self.state.lock().unwrap();
+        match state.subscribers.get_mut(&fh) {
+            Some(subscriber) if !subscriber.buffer.is_empty() => reply.data(&subscriber.take(size)),
+            Some(subscriber) if subscriber.nonblocking => reply.error(EAGAIN),
+            Some(subscriber) => {
+                if let Some((previous, _, _)) = subscriber.pending_read.replace((reply, size, Instant::now())) {
+                    previous.data(&[]);
+                }
+            }
+            //not open for reading
+            None => reply.data(&[]),
+        }
+    }
+
+    pub fn poll(&self, fh: u64, ph: PollHandle, reply: ReplyPoll) {
+        let mut state = self.state.lock().unwrap();
+        let mut events = POLLOUT as u32;
+        if let Some(subscriber) = state.subscribers.get_mut(&fh) {
+            if subscriber.buffer.is_empty() {
+                subscriber.poll_handle = Some(ph);
+            } else {
+                events |= POLLIN as u32;
+            }
+        }
+        reply.poll(events);
+    }
+}
+
+/// Send each line of `data` as a notification on the channel
+pub fn notify(client: &mut Client, statements: &mut Statements, channel: &str, data: &[u8]) -> Result<(), postgres::Error> {
+    for line in String::from_utf8_lossy(data).lines().filter(|line| !line.is_empty()) {
+        statements.execute(client, "select pg_notify($1, $2)", &[&channel, &line])?;
+    }
+    Ok(())
+}
+
+fn listen(mut client: Client, state: Arc<Mutex<HubState>>) {
+    loop {
+        let to_listen: Vec<String> = state.lock().unwrap().to_listen.drain(..).collect();
+        for channel in to_listen {
+            let statement = format!("LISTEN \"{}\"", channel.replace('"', "\"\""));
+            if let Err(e) = client.batch_execute(&statement) {
+                log::error!("Unable to listen on channel {}: {}", channel, e);
+                continue;
+            }
+            state.lock().unwrap().listening.insert(channel);
+        }
+
+        let mut notifications = client.notifications();
+        let mut received = vec![];
+        match notifications.timeout_iter(LISTEN_TIMEOUT).next() {
+            Ok(Some(notification)) => received.push(notification),
+            Ok(None) => {}
+            Err(e) => {
+                log::error!("Lost the connection listening for notifications: {}", e);
+                return;
+            }
+        }
+        let mut waiting = notifications.iter();
+        while let Ok(Some(notification)) = waiting.next() {
+            received.push(notification);
+        }
+
+        let mut state = state.lock().unwrap();
+        for notification in received {
+            for subscriber in state.subscribers.values_mut().filter(|s| s.channel == notification.channel()) {
+                subscriber.buffer.extend_from_slice(notification.payload().as_bytes());
+                subscriber.buffer.push(b'\n');
+                if let Some((reply, size, _)) = subscriber.pending_read.take() {
+                    reply.data(&subscriber.take(size));
+                }
+                if let Some(ph) = subscriber.poll_handle.take()
+                    && let Err(e) = ph.notify() {
+                    log::warn!("Unable to notify poll: {}", e);
+                }
+            }
+        }
+        for subscriber in state.subscribers.values_mut() {
+            if let Some((_, _, since)) = subscriber.pending_read.as_ref()
+                && since.elapsed() >= READ_TIMEOUT
+                && let Some((reply, _, _)) = subscriber.pending_read.take() {
+                reply.data(&[]);
+            }
+        }
+    }
+}
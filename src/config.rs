@@ -4,6 +4,7 @@
 //! Example:
 //! ```
 //! mountpoint = "/tmp/pgfs/"
+//! capacity = "50G"
 //!
 //! [database]
 //!
@@ -29,6 +30,10 @@
 //! name_field = "name"
 //! data_query = "select id, 'image_'||id || regexp_replace(mime_type, '^.*/','.') as name, octet_length(image) as length from pics;"
 //! readonly = true
+//! xattr_fields = ["mime_type", "description"]
+//! entry_ttl = 300
+//! attr_ttl = 300
+//! keep_cache = true
 //!
 //! [filestest]
 //!
@@ -39,13 +44,62 @@
 //! data_field = "file"
 //! created_date_field = "created"
 //! modified_date_field = "modified"
-//! data_query = "select id, name,  octet_length(file) as length, created, modified from files_test"
+//! data_query = "select id, name,  octet_length(file) as length, created, modified, mode, owner_uid from files_test"
 //! readonly = false
+//! version_field = "version"
+//! conflict_copy = true
+//! write_strategy = "whole_file"
+//! history_table = "files_history"
+//! deleted_field = "deleted_at"
+//! mode_field = "mode"
+//! owner_field = "owner_uid"
+//!
+//! [reports]
+//!
+//! table_name = "reports"
+//! data_query = "select id, name, 0 as length from reports"
+//! read_function = "run_report"
+//! write_function = "save_report_params"
+//! function_params = ["name"]
+//!
+//! [channels]
+//!
+//! directory = "channels"
+//! names = ["jobs", "events"]
+//!
+//! [change_feed]
+//!
+//! channel = "pgfs_changes"
+//!
+//! [audit]
+//!
+//! table = "pgfs_audit"
+//!
+//! [roles]
+//!
+//! 1000 = "paul"
+//! default = "pgfs_guest"
+//!
+//! [read_cache]
+//!
+//! size = "64M"
+//! readahead = "1M"
+//!
+//! [write_cache]
+//!
+//! size = "64M"
+//! flush_after = 5
 //!```
 //!
 
+use crate::block_cache::CacheConfig;
+use crate::quota::parse_size;
+use crate::roles::Roles;
+use crate::staging::StageConfig;
+use crate::write_buffer::BufferConfig;
 use std::collections::HashMap;
 use std::error::Error;
+use std::time::Duration;
 use toml::Value;
 
 /// Main config object - contains config for connecting to a postgres database and a TableConfig
@@ -58,6 +112,46 @@ pub struct PgfsConfig {
     pub table_config: HashMap<String,TableConfig>,
     pub connection_string: Option<String>,
     pub mountpoint: String,
+    pub channels: Option<ChannelConfig>,
+    ///When set (by a `[change_feed]` section), the channel which triggers installed by `pgfs install-triggers`
+    /// notify of changes, so that changes made by other applications show up without remounting.
+    pub change_channel: Option<String>,
+    ///Mount a read only, point-in-time snapshot of the database. Set `snapshot = true` to export a new
+    /// snapshot when mounting, or `snapshot = "<id>"` to use one exported by another session.
+    pub snapshot: bool,
+    pub snapshot_id: Option<String>,
+    ///Table to record changes made through the mount in. See the audit module for the columns it needs.
+    pub audit_table: Option<String>,
+    ///PostgreSQL roles to make requests as, by the uid of the calling process. See the roles module.
+    pub roles: Option<Roles>,
+    ///Space available for the database, in bytes. Writes which would go past it fail with ENOSPC.
+    pub capacity: Option<u64>,
+    ///Send every statement unprepared, for connecting through a pooler in transaction mode. See the
+    /// statements module.
+    pub pgbouncer_transaction_mode: bool,
+    ///Hold fcntl and flock locks as advisory locks in the database, so that every mount respects them. With
+    /// `file_locks = false` the kernel keeps them, and they only apply within this mount.
+    pub file_locks: bool,
+    ///Read file content and write out buffered ranges on tokio-postgres connections, pipelining queries.
+    /// See the pipeline module.
+    pub async_backend: bool,
+    ///Sizes for the cache of blocks read from files, from the `[read_cache]` section. See the block_cache module.
+    pub read_cache: CacheConfig,
+    ///Limits on buffering writes, from the `[write_cache]` section. See the write_buffer module.
+    pub write_cache: BufferConfig,
+    ///Where files of tables with the whole file write strategy are staged, also from `[write_cache]`. See the
+    /// staging module.
+    pub staging: StageConfig,
+}
+
+/// Config for exposing LISTEN/NOTIFY channels as files, from the `[channels]` section. Channels can also be
+/// added by creating a file in the channels directory.
+#[derive(Clone, Debug)]
+pub struct ChannelConfig {
+    ///Directory name under the root directory. Defaults to `channels`
+    pub directory: String,
+    ///Channels which are listed in the directory from startup
+    pub names: Vec<String>,
 }
 
 /// The config for an individual table/query. If mapping a table with a file as bytea, a name, an id
@@ -86,18 +180,102 @@ pub struct TableConfig {
     pub data_query: String,
     pub create_query: Option<String>,
     pub update_query: Option<String>,
+    ///Statement run to delete a file, with the id as `$1` - for example to mark the row as archived.
+    /// Defaults to deleting the row.
     pub delete_query: Option<String>,
     pub read_only: bool,
-    ///Optionally provide the uid for the owner of the files in this table. By default this is set to the user
-    /// who mounts the directory. Maybe it should default to the owner of the mount (or that could be a config)
+    ///Optionally provide the uid for the owner of the files in this table. By default this is the owner of the
+    /// mountpoint.
     pub uid: Option<u32>,
-    ///Optionally provide the gid for the group owner of the files in this table. By default this is set to the group
-    /// of the user who mounts the directory.
+    ///Optionally provide the gid for the group owner of the files in this table. By default this is the group
+    /// of the mountpoint.
     pub gid: Option<u32>,
+    ///Optionally provide the permission bits for the files in this table, such as `0o640`. Defaults to `0o755`.
+    /// Bits are taken away for anything the database role isn't allowed to do.
+    pub mode: Option<u16>,
+    ///Column holding the permission bits of each file, set by `chmod`. Files where it is null have the table's
+    /// `mode`. It must be selected by the data_query, as must `owner_field` and `group_field`.
+    pub mode_field: Option<String>,
+    ///Column holding the uid of each file's owner, set by `chown`. Falls back to the table's `uid`.
+    pub owner_field: Option<String>,
+    ///Column holding the gid of each file's group, set by `chown` or `chgrp`. Falls back to the table's `gid`.
+    pub group_field: Option<String>,
+    ///Columns shown as `user.pgfs.<column>` extended attributes of each file, and set by setting them.
+    pub xattr_fields: Vec<String>,
+    ///A jsonb column to keep any other `user.` extended attributes in.
+    pub xattr_column: Option<String>,
+    ///Most bytes the files in this table can hold between them. Writes past it fail with EDQUOT.
+    pub quota_bytes: Option<u64>,
+    ///Most files this table can hold. Creating more fails with EDQUOT.
+    pub quota_files: Option<u64>,
+    ///Most bytes of writes to buffer for each file before writing them out, instead of `file_size` from
+    /// `[write_cache]`. 0 writes each write straight to the database.
+    pub write_cache: Option<u64>,
 
     pub created_date_field:Option<String>,
     pub modified_date_field: Option<String>,
-    
+    ///Name of a SQL function which returns the content of each file (as bytea or text). When set, reads call
+    /// this function instead of selecting the data field, and the result is held for as long as the file is open.
+    pub read_function: Option<String>,
+    ///Name of a SQL function which is called with the whole content of a file (as bytea) when it is closed after
+    /// being written.
+    pub write_function: Option<String>,
+    ///Parameters passed to read_function and write_function, in order. Each is one of `id` (the id field of the row),
+    /// `name` (the file name) or `table` (the directory name). The payload is passed after these for write_function.
+    pub function_params: Vec<String>,
+    ///Write each file in a transaction which is committed when the file is closed (or fsynced), so that
+    /// nothing else sees a partly written file, and a failed write is rolled back. Each file being written
+    /// uses a connection of its own.
+    pub write_transactions: bool,
+    ///Only write back a file if its row hasn't been changed by anything else since the file was opened,
+    /// so that two writers don't silently overwrite each other. Without a version_field the row's `xmin`
+    /// is used to tell whether it has changed.
+    pub optimistic_locking: bool,
+    ///An integer column which is incremented on every write, used instead of `xmin` for optimistic locking.
+    /// Setting this turns on optimistic_locking.
+    pub version_field: Option<String>,
+    ///When optimistic locking finds that a file has been changed by someone else, save what is being written
+    /// as a new file named `<name>.conflict-<timestamp>` instead of failing the write. Needs write_transactions
+    /// or the whole_file write strategy, so that the conflict is found before any of the file has been written
+    /// and the copy ends up with all of it.
+    pub conflict_copy: bool,
+    ///Set by `write_strategy = "whole_file"`: stage files opened for writing locally and write each back with a
+    /// single UPDATE when it is closed, rather than writing ranges as they come. For tables whose files are
+    /// always rewritten whole.
+    pub whole_file: bool,
+    ///Seconds the kernel may remember names in this table's directory before looking them up again. Lookups
+    /// carry a single timeout for the name and the attributes, so they get the shorter of this and `attr_ttl`.
+    pub entry_ttl: Option<Duration>,
+    ///Seconds the kernel may keep the attributes (size, times) of this table's files. Defaults to 1.
+    pub attr_ttl: Option<Duration>,
+    ///Keep the page cache of files between opens, so tables which rarely change are read from memory. Pages
+    /// are dropped when the change feed reports a change, or when a file's size or mtime is seen to change.
+    pub keep_cache: bool,
+    ///Bypass the page cache altogether, so every read goes to the database. Wins over `keep_cache`.
+    pub direct_io: bool,
+    ///Table to copy the previous content of a file into each time it is written, which makes the versions
+    /// available under `.versions/<name>/` in the table's directory. See the history module for the columns
+    /// it needs.
+    pub history_table: Option<String>,
+    ///Column marking a row as deleted, which turns on soft delete: deleting a file sets this column rather
+    /// than deleting the row, and the file moves to the table's `.trash` directory. Rows where it is
+    /// live_value (by default null) are listed as normal.
+    pub deleted_field: Option<String>,
+    ///SQL expression the deleted_field is set to when a file is deleted. Defaults to `now()`, which suits a
+    /// `deleted_at` timestamp. For a status column use something like `'deleted'`, with live_value set too.
+    pub deleted_value: Option<String>,
+    ///SQL expression the deleted_field holds for rows which aren't deleted, and is set back to when a file
+    /// is restored from the trash. Defaults to `null`; a status column needs something like `'active'`.
+    pub live_value: Option<String>,
+    ///Columns holding the period each version of a row is valid for, in tables which keep system-period
+    /// history. A null valid_to means the row is current. Setting both lets the table be browsed as of any
+    /// time, through `@<timestamp>` directories or `--as-of` on the command line. The table_name of such a
+    /// table can't be schema qualified.
+    pub valid_from_field: Option<String>,
+    pub valid_to_field: Option<String>,
+    ///Table holding old versions of rows, with the same columns, for tables which keep their history
+    /// separately (as temporal_tables does).
+    pub temporal_history_table: Option<String>,
 }
 
 impl PgfsConfig {
@@ -107,6 +285,19 @@ impl PgfsConfig {
             table_config: HashMap::new(),
             connection_string: None,
             mountpoint: "/tmp/pgfs".to_string(),
+            channels: None,
+            change_channel: None,
+            snapshot: false,
+            snapshot_id: None,
+            audit_table: None,
+            roles: None,
+            capacity: None,
+            pgbouncer_transaction_mode: false,
+            file_locks: true,
+            async_backend: false,
+            read_cache: CacheConfig::default(),
+            write_cache: BufferConfig::default(),
+            staging: StageConfig::default(),
         };
 
         let empty_string_value = Value::String("".to_string());
@@ -128,6 +319,87 @@ impl PgfsConfig {
             result.mountpoint = mountpoint_value.as_str().unwrap_or(result.mountpoint.as_str()).to_string();
         }
 
+        if let Some(channels) = tml.get("channels") {
+            let mut channel_config = ChannelConfig {
+                directory: "channels".to_string(),
+                names: vec![],
+            };
+            if let Some(directory) = channels.get("directory") {
+                channel_config.directory = directory.as_str().unwrap().to_string();
+            }
+            if let Some(names) = channels.get("names") {
+                channel_config.names = string_list(names);
+            }
+            result.channels = Some(channel_config);
+        }
+
+        match tml.get("snapshot") {
+            Some(Value::Boolean(snapshot)) => result.snapshot = *snapshot,
+            Some(Value::String(id)) => {
+                result.snapshot = true;
+                result.snapshot_id = Some(id.to_string());
+            }
+            _ => {}
+        }
+
+        if let Some(audit) = tml.get("audit") {
+            result.audit_table = audit.get("table").and_then(|table| table.as_str()).map(|table| table.to_string());
+        }
+
+        if let Some(roles) = tml.get("roles").and_then(|roles| roles.as_table()) {
+            let mut role_config = Roles::default();
+            for (key, role) in roles {
+                let role = role.as_str().ok_or(format!("role for {} must be a string", key))?.to_string();
+                if key == "default" {
+                    role_config.default = Some(role);
+                } else {
+                    let uid = key.parse::<u32>().map_err(|_| format!("{} in [roles] is not a uid", key))?;
+                    role_config.by_uid.insert(uid, role);
+                }
+            }
+            result.roles = Some(role_config);
+        }
+
+        result.capacity = tml.get("capacity").and_then(parse_size);
+        result.pgbouncer_transaction_mode = tml.get("pgbouncer_transaction_mode").and_then(|mode| mode.as_bool()).unwrap_or(false);
+        result.file_locks = tml.get("file_locks").and_then(|enabled| enabled.as_bool()).unwrap_or(true);
+        result.async_backend = tml.get("async_backend").and_then(|enabled| enabled.as_bool()).unwrap_or(false);
+
+        if let Some(read_cache) = tml.get("read_cache") {
+            for (key, size) in [("size", &mut result.read_cache.size), ("block_size", &mut result.read_cache.block_size),
+                                ("readahead", &mut result.read_cache.readahead), ("whole_file", &mut result.read_cache.whole_file)] {
+                if let Some(value) = read_cache.get(key) {
+                    *size = parse_size(value).ok_or(format!("read_cache {} is not a size", key))?;
+                }
+            }
+            if result.read_cache.block_size == 0 {
+                return Err("read_cache block_size can't be 0".into());
+            }
+        }
+
+        if let Some(write_cache) = tml.get("write_cache") {
+            for (key, size) in [("size", &mut result.write_cache.size), ("file_size", &mut result.write_cache.file_size)] {
+                if let Some(value) = write_cache.get(key) {
+                    *size = parse_size(value).ok_or(format!("write_cache {} is not a size", key))?;
+                }
+            }
+            if let Some(flush_after) = write_cache.get("flush_after") {
+                let seconds = flush_after.as_integer().ok_or("write_cache flush_after must be a number of seconds")?;
+                result.write_cache.flush_after = Duration::from_secs(seconds as u64);
+            }
+            if let Some(memory) = write_cache.get("stage_in_memory") {
+                result.staging.memory = parse_size(memory).ok_or("write_cache stage_in_memory is not a size")?;
+            }
+            if let Some(dir) = write_cache.get("stage_dir") {
+                result.staging.dir = Some(dir.as_str().ok_or("write_cache stage_dir must be a path")?.into());
+            }
+        }
+
+        if let Some(change_feed) = tml.get("change_feed") {
+            let channel = change_feed.get("channel").and_then(|c| c.as_str()).unwrap_or(crate::changes::DEFAULT_CHANNEL);
+            result.change_channel = Some(channel.to_string());
+        }
+
         //get defaults
         let mut defaults = TableConfig {
             table_name: "".to_string(),
@@ -143,100 +415,246 @@ impl PgfsConfig {
             read_only:true,
             uid: None,
             gid: None,
+            mode: None,
+            mode_field: None,
+            owner_field: None,
+            group_field: None,
+            xattr_fields: vec![],
+            xattr_column: None,
+            quota_bytes: None,
+            quota_files: None,
+            write_cache: None,
             created_date_field:None,
             modified_date_field: None,
+            read_function: None,
+            write_function: None,
+            function_params: vec![],
+            write_transactions: false,
+            optimistic_locking: false,
+            version_field: None,
+            conflict_copy: false,
+            whole_file: false,
+            entry_ttl: None,
+            attr_ttl: None,
+            keep_cache: false,
+            direct_io: false,
+            history_table: None,
+            deleted_field: None,
+            deleted_value: None,
+            live_value: None,
+            valid_from_field: None,
+            valid_to_field: None,
+            temporal_history_table: None,
 
       //      database: None,
       //      user: None,
       //      pass: None
         };
         if let Some(default) = tml.get("default") {
-            if let Some(table_name) = default.get("table_name") {
-                defaults.table_name = table_name.as_str().unwrap().to_string();
-            }
-            if let Some(data_type) = default.get("data_type") {
-                defaults.data_type = data_type.as_str().unwrap().to_string();
-            }
-            if let Some(id_field) = default.get("id_field") {
-                defaults.id_field = id_field.as_str().unwrap().to_string();
-            }
-            if let Some(length_field) = default.get("length_field") {
-                defaults.length_field = length_field.as_str().unwrap().to_string();
-            }
-            if let Some(data_field) = default.get("data_field") {
-                defaults.data_field = data_field.as_str().unwrap().to_string();
-            }
-            if let Some(name_field) = default.get("name_field") {
-                defaults.name_field = name_field.as_str().unwrap().to_string();
-            }
-            if let Some(data_query) = default.get("data_query") {
-                defaults.data_query = data_query.as_str().unwrap().to_string();
-            }
-            if let Some(read_only) = default.get("read_only") {
-                defaults.read_only = read_only.as_bool().unwrap_or(true);
-            }
-            if let Some(uid) = default.get("uid") {
-                defaults.uid = uid.as_integer().map(|x|x as u32);
-            }
-            if let Some(gid) = default.get("gid") {
-                defaults.gid = gid.as_integer().map(|x|x as u32);
-            }
-            if let Some(created_date_field) = default.get("created_date_field") {
-                defaults.created_date_field = Some(created_date_field.as_str().unwrap().to_string());
-            }
-            if let Some(modified_date_field) = default.get("modified_date_field") {
-                defaults.modified_date_field = Some(modified_date_field.as_str().unwrap().to_string());
-            }
-       }
+            apply_table_settings(&mut defaults, default)?;
+        }
 
         let tables = tml.as_table().unwrap();
         for (table_name, table) in tables.iter() {
-            if table_name == "default" || table_name == "database" || table_name == "mountpoint" {
+            if table_name == "default" || table_name == "database" || table_name == "mountpoint" || table_name == "snapshot" || table_name == "channels" || table_name == "change_feed" || table_name == "audit" || table_name == "roles" || table_name == "capacity" || table_name == "read_cache" || table_name == "write_cache" || table_name == "pgbouncer_transaction_mode" || table_name == "async_backend" || table_name == "file_locks" {
                 continue
             }
             let mut t = defaults.clone();
-            if let Some(table_name) = table.get("table_name") {
-                t.table_name = table_name.as_str().unwrap().to_string();
-            }
-            if let Some(data_type) = table.get("data_type") {
-                t.data_type = data_type.as_str().unwrap().to_string();
-            }
-            if let Some(id_field) = table.get("id_field") {
-                t.id_field = id_field.as_str().unwrap().to_string();
-            }
-            if let Some(length_field) = table.get("length_field") {
-                t.length_field = length_field.as_str().unwrap().to_string();
-            }
-            if let Some(data_field) = table.get("data_field") {
-                t.data_field = data_field.as_str().unwrap().to_string();
-            }
-            if let Some(name_field) = table.get("name_field") {
-                t.name_field = name_field.as_str().unwrap().to_string();
-            }
-            if let Some(data_query) = table.get("data_query") {
-                t.data_query = data_query.as_str().unwrap().to_string();
-            }
-            if let Some(read_only) = table.get("read_only") {
-                t.read_only = read_only.as_bool().unwrap_or(true);
+            apply_table_settings(&mut t, table)?;
+            if t.conflict_copy && !t.write_transactions && !t.whole_file {
+                return Err(format!("conflict_copy in {} needs write_transactions or write_strategy = \"whole_file\"", table_name).into());
             }
-            if let Some(uid) = table.get("uid") {
-                t.uid = uid.as_integer().map(|x|x as u32);
-            }
-            if let Some(gid) = table.get("gid") {
-                t.gid = gid.as_integer().map(|x|x as u32);
-            }
-            if let Some(created_date_field) = table.get("created_date_field") {
-                t.created_date_field = Some(created_date_field.as_str().unwrap().to_string());
-            }
-            if let Some(modified_date_field) = table.get("modified_date_field") {
-                t.modified_date_field = Some(modified_date_field.as_str().unwrap().to_string());
+            //as of queries hide the table behind a CTE of the same name, which can't have a schema
+            if t.valid_from_field.is_some() && t.valid_to_field.is_some() && t.table_name.contains('.') {
+                return Err(format!("{} keeps history, so its table_name {} can't be schema qualified; set search_path instead", table_name, t.table_name).into());
             }
 
             result.table_config.insert(table_name.to_string(), t);
         }
 
+        result.check_transaction_mode()?;
 
         Ok(result)
 
     }
+
+    /// Refuse features which need a session of their own when connecting through a pooler in
+    /// transaction mode, as the session can change from one statement to the next
+    pub fn check_transaction_mode(&self) -> Result<(), String> {
+        if !self.pgbouncer_transaction_mode {
+            return Ok(());
+        }
+        let mut features = vec![];
+        if self.roles.is_some() {
+            features.push("[roles]".to_string());
+        }
+        if self.snapshot {
+            features.push("snapshot".to_string());
+        }
+        if self.change_channel.is_some() {
+            features.push("[change_feed]".to_string());
+        }
+        if self.channels.is_some() {
+            features.push("[channels]".to_string());
+        }
+        if self.file_locks {
+            features.push("file locks (set file_locks = false)".to_string());
+        }
+        //files staged beyond stage_in_memory are copied to the server through a temporary table
+        for (name, _) in self.table_config.iter().filter(|(_, table)| table.whole_file) {
+            features.push(format!("write_strategy = \"whole_file\" in [{}]", name));
+        }
+        match features.is_empty() {
+            true => Ok(()),
+            false => Err(format!("pgbouncer_transaction_mode can't be used with {}", features.join(", "))),
+        }
+    }
+}
+
+/// Override the settings in `t` with any values present in the toml table. Used for both the
+/// `[default]` section and for each table section, so every setting can be given a default.
+fn apply_table_settings(t: &mut TableConfig, table: &Value) -> Result<(), String> {
+    if let Some(table_name) = table.get("table_name") {
+        t.table_name = table_name.as_str().unwrap().to_string();
+    }
+    if let Some(data_type) = table.get("data_type") {
+        t.data_type = data_type.as_str().unwrap().to_string();
+    }
+    if let Some(id_field) = table.get("id_field") {
+        t.id_field = id_field.as_str().unwrap().to_string();
+    }
+    if let Some(length_field) = table.get("length_field") {
+        t.length_field = length_field.as_str().unwrap().to_string();
+    }
+    if let Some(data_field) = table.get("data_field") {
+        t.data_field = data_field.as_str().unwrap().to_string();
+    }
+    if let Some(name_field) = table.get("name_field") {
+        t.name_field = name_field.as_str().unwrap().to_string();
+    }
+    if let Some(data_query) = table.get("data_query") {
+        t.data_query = data_query.as_str().unwrap().to_string();
+    }
+    if let Some(read_only) = table.get("read_only") {
+        t.read_only = read_only.as_bool().unwrap_or(true);
+    }
+    if let Some(uid) = table.get("uid") {
+        t.uid = uid.as_integer().map(|x|x as u32);
+    }
+    if let Some(gid) = table.get("gid") {
+        t.gid = gid.as_integer().map(|x|x as u32);
+    }
+    if let Some(mode) = table.get("mode") {
+        //either an integer (mode = 0o640) or an octal string (mode = "640")
+        t.mode = mode.as_integer().map(|x| x as u16)
+            .or_else(|| mode.as_str().and_then(|x| u16::from_str_radix(x, 8).ok()));
+    }
+    if let Some(mode_field) = table.get("mode_field") {
+        t.mode_field = mode_field.as_str().map(|x| x.to_string());
+    }
+    if let Some(owner_field) = table.get("owner_field") {
+        t.owner_field = owner_field.as_str().map(|x| x.to_string());
+    }
+    if let Some(group_field) = table.get("group_field") {
+        t.group_field = group_field.as_str().map(|x| x.to_string());
+    }
+    if let Some(xattr_fields) = table.get("xattr_fields") {
+        t.xattr_fields = string_list(xattr_fields);
+    }
+    if let Some(xattr_column) = table.get("xattr_column") {
+        t.xattr_column = xattr_column.as_str().map(|x| x.to_string());
+    }
+    if let Some(quota_bytes) = table.get("quota_bytes") {
+        t.quota_bytes = parse_size(quota_bytes);
+    }
+    if let Some(quota_files) = table.get("quota_files") {
+        t.quota_files = quota_files.as_integer().map(|x| x as u64);
+    }
+    if let Some(write_cache) = table.get("write_cache") {
+        t.write_cache = parse_size(write_cache);
+    }
+    if let Some(created_date_field) = table.get("created_date_field") {
+        t.created_date_field = Some(created_date_field.as_str().unwrap().to_string());
+    }
+    if let Some(modified_date_field) = table.get("modified_date_field") {
+        t.modified_date_field = Some(modified_date_field.as_str().unwrap().to_string());
+    }
+    if let Some(read_function) = table.get("read_function") {
+        t.read_function = Some(read_function.as_str().unwrap().to_string());
+    }
+    if let Some(write_function) = table.get("write_function") {
+        t.write_function = Some(write_function.as_str().unwrap().to_string());
+    }
+    if let Some(function_params) = table.get("function_params") {
+        t.function_params = string_list(function_params);
+        if let Some(unknown) = t.function_params.iter().find(|param| !["id", "name", "table"].contains(&param.as_str())) {
+            return Err(format!("{} in function_params is not one of id, name or table", unknown));
+        }
+    }
+    if let Some(write_transactions) = table.get("write_transactions") {
+        t.write_transactions = write_transactions.as_bool().unwrap_or(false);
+    }
+    if let Some(optimistic_locking) = table.get("optimistic_locking") {
+        t.optimistic_locking = optimistic_locking.as_bool().unwrap_or(false);
+    }
+    if let Some(version_field) = table.get("version_field") {
+        t.version_field = Some(version_field.as_str().unwrap().to_string());
+        t.optimistic_locking = true;
+    }
+    if let Some(conflict_copy) = table.get("conflict_copy") {
+        t.conflict_copy = conflict_copy.as_bool().unwrap_or(false);
+    }
+    if let Some(write_strategy) = table.get("write_strategy") {
+        t.whole_file = write_strategy.as_str() == Some("whole_file");
+    }
+    if let Some(entry_ttl) = table.get("entry_ttl") {
+        t.entry_ttl = seconds(entry_ttl);
+    }
+    if let Some(attr_ttl) = table.get("attr_ttl") {
+        t.attr_ttl = seconds(attr_ttl);
+    }
+    if let Some(keep_cache) = table.get("keep_cache") {
+        t.keep_cache = keep_cache.as_bool().unwrap_or(false);
+    }
+    if let Some(direct_io) = table.get("direct_io") {
+        t.direct_io = direct_io.as_bool().unwrap_or(false);
+    }
+    if let Some(delete_query) = table.get("delete_query") {
+        t.delete_query = Some(delete_query.as_str().unwrap().to_string());
+    }
+    if let Some(deleted_field) = table.get("deleted_field") {
+        t.deleted_field = Some(deleted_field.as_str().unwrap().to_string());
+    }
+    if let Some(deleted_value) = table.get("deleted_value") {
+        t.deleted_value = Some(deleted_value.as_str().unwrap().to_string());
+    }
+    if let Some(live_value) = table.get("live_value") {
+        t.live_value = Some(live_value.as_str().unwrap().to_string());
+    }
+    if let Some(valid_from_field) = table.get("valid_from_field") {
+        t.valid_from_field = Some(valid_from_field.as_str().unwrap().to_string());
+    }
+    if let Some(valid_to_field) = table.get("valid_to_field") {
+        t.valid_to_field = Some(valid_to_field.as_str().unwrap().to_string());
+    }
+    if let Some(temporal_history_table) = table.get("temporal_history_table") {
+        t.temporal_history_table = Some(temporal_history_table.as_str().unwrap().to_string());
+    }
+    if let Some(history_table) = table.get("history_table") {
+        t.history_table = Some(history_table.as_str().unwrap().to_string());
+    }
+    Ok(())
+}
+
+/// A duration given in seconds, whole or fractional
+fn seconds(value: &Value) -> Option<Duration> {
+    value.as_float().or(value.as_integer().map(|x| x as f64))
+        .filter(|seconds| *seconds >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+fn string_list(value: &Value) -> Vec<String> {
+    value.as_array()
+        .map(|values| values.iter().filter_map(|v| v.as_str().map(|s| s.to_string())).collect())
+        .unwrap_or_default()
 }
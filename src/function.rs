@@ -0,0 +1,56 @@
+//! Function backed files.
+//!
+//! For tables with a `read_function`, the content of a file is whatever the function returns rather than
+//! the value of a column, which suits reports generated in PL/pgSQL. The content is fetched once per
+//! open file handle so that a reader sees a consistent snapshot across many small `read` calls.
+//! With a `write_function` the whole file is passed to the function when it is closed.
+
+use crate::statements::Statements;
+use postgres::Client;
+use postgres::types::ToSql;
+
+/// Build `select function($1, $2, ...)` with one placeholder per configured parameter, plus one for the
+/// payload if `with_payload` is set.
+pub fn function_query_string(function: &str, params: &[String], with_payload: bool) -> String {
+    let count = params.len() + if with_payload { 1 } else { 0 };
+    let placeholders: Vec<String> = (1..=count).map(|i| format!("${}", i)).collect();
+    format!("select {}({})", function, placeholders.join(", "))
+}
+
+/// Values for the configured function parameters for a single file
+pub struct FunctionArgs<'a> {
+    pub id: i32,
+    pub name: &'a str,
+    pub table: &'a str,
+}
+
+impl FunctionArgs<'_> {
+    fn values<'b>(&'b self, params: &[String]) -> Vec<&'b (dyn ToSql + Sync)> {
+        params.iter().map(|param| match param.as_str() {
+            "id" => &self.id as &(dyn ToSql + Sync),
+            "table" => &self.table as &(dyn ToSql + Sync),
+            //anything else was refused when the config was read
+            _ => &self.name as &(dyn ToSql + Sync),
+        }).collect()
+    }
+}
+
+/// Call the read function and return the content of the file. Functions may return bytea or text.
+pub fn read_content(client: &mut Client, statements: &mut Statements, query: &str, params: &[String], args: &FunctionArgs) -> Result<Vec<u8>, postgres::Error> {
+    let Some(row) = statements.query_opt(client, query, &args.values(params))? else {
+        return Ok(vec![]);
+    };
+    if let Ok(bytes) = row.try_get::<usize, Option<Vec<u8>>>(0) {
+        return Ok(bytes.unwrap_or_default());
+    }
+    let text = row.try_get::<usize, Option<String>>(0)?;
+    Ok(text.unwrap_or_default().into_bytes())
+}
+
+/// Call the write function with the whole content of the file
+pub fn write_content(client: &mut Client, statements: &mut Statements, query: &str, params: &[String], args: &FunctionArgs, content: &[u8]) -> Result<(), postgres::Error> {
+    let mut values = args.values(params);
+    values.push(&content);
+    statements.query(client, query, &values)?;
+    Ok(())
+}
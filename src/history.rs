@@ -0,0 +1,97 @@
+//! File version history.
+//!
+//! For tables with a `history_table`, the content of a file is copied into the history table just before
+//! it is first changed after being opened for writing, along with the time and the uid of the writer, so
+//! each write session which is committed leaves the previous content behind. Each table directory then has
+//! a read-only `.versions` directory holding a directory for each file with history, and in that one file
+//! per saved version, named by the time it was replaced. Copying a version back over the file restores it
+//! (saving the content it replaces as another version).
+//!
+//! The history table needs these columns:
+//! ```sql
+//! create table files_history (
+//!     id serial primary key,
+//!     file_id int not null,
+//!     content bytea,
+//!     saved_at timestamptz not null default now(),
+//!     writer_uid int
+//! );
+//! ```
+
+use crate::Inode;
+use crate::statements::Statements;
+use postgres::Client;
+use std::time::SystemTime;
+
+pub const VERSIONS_DIR: &str = ".versions";
+
+/// Something in a table's `.versions` directory
+#[derive(Clone, Copy, Debug)]
+pub enum HistoryNode {
+    ///the `.versions` directory itself
+    Files { table_inode: Inode },
+    ///the directory of versions of one file
+    File { table_inode: Inode, file_id: i32 },
+    ///a saved version
+    Version { table_inode: Inode, version_id: i32 },
+}
+
+impl HistoryNode {
+    pub fn table_inode(&self) -> Inode {
+        match self {
+            HistoryNode::Files { table_inode } => *table_inode,
+            HistoryNode::File { table_inode, .. } => *table_inode,
+            HistoryNode::Version { table_inode, .. } => *table_inode,
+        }
+    }
+}
+
+/// A saved version of a file
+pub struct Version {
+    pub id: i32,
+    pub name: String,
+    pub size: u64,
+    pub saved_at: SystemTime,
+}
+
+/// The table a history table belongs to
+pub struct Source<'a> {
+    pub history_table: &'a str,
+    pub table_name: &'a str,
+    pub id_field: &'a str,
+    pub name_field: &'a str,
+    pub data_field: &'a str,
+}
+
+/// Copy the current content of a row into the history table
+pub fn save(client: &mut Client, statements: &mut Statements, source: &Source, id: i32, uid: u32) -> Result<(), postgres::Error> {
+    let query = format!("insert into {} (file_id, content, writer_uid) select {id}, {}, $2 from {} where {id} = $1",
+        source.history_table, source.data_field, source.table_name, id = source.id_field);
+    statements.execute(client, query.as_str(), &[&id, &(uid as i32)])?;
+    Ok(())
+}
+
+/// Id and current name of every file which has history
+pub fn files(client: &mut Client, statements: &mut Statements, source: &Source) -> Result<Vec<(i32, String)>, postgres::Error> {
+    let query = format!("select distinct h.file_id, t.{} from {} h join {} t on t.{} = h.file_id",
+        source.name_field, source.history_table, source.table_name, source.id_field);
+    Ok(statements.query(client, query.as_str(), &[])?.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+/// The saved versions of a file, oldest first
+pub fn versions(client: &mut Client, statements: &mut Statements, source: &Source, file_id: i32) -> Result<Vec<Version>, postgres::Error> {
+    let query = format!("select id, to_char(saved_at at time zone 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"'), \
+        octet_length(content), saved_at from {} where file_id = $1 order by saved_at", source.history_table);
+    Ok(statements.query(client, query.as_str(), &[&file_id])?.iter().map(|row| Version {
+        id: row.get(0),
+        name: row.get(1),
+        size: row.get::<usize, Option<i32>>(2).unwrap_or(0) as u64,
+        saved_at: row.get(3),
+    }).collect())
+}
+
+pub fn read(client: &mut Client, statements: &mut Statements, history_table: &str, version_id: i32, offset: i64, size: u32) -> Result<Vec<u8>, postgres::Error> {
+    let query = format!("select substring(content, $2, $3) from {} where id = $1", history_table);
+    let row = statements.query_opt(client, query.as_str(), &[&version_id, &(offset as i32 + 1), &(size as i32)])?;
+    Ok(row.and_then(|row| row.get::<usize, Option<Vec<u8>>>(0)).unwrap_or_default())
+}
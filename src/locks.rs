@@ -0,0 +1,347 @@
+//! POSIX (fcntl) and BSD (flock) locks backed by PostgreSQL advisory locks.
+//!
+//! While anything in this mount holds a lock on a file, a session level advisory lock keyed on
+//! `(hashtext(table), id)` is held on a connection of its own, so locks are respected by every pgfs mount
+//! using the same database. Read locks take the advisory lock shared and write locks take it exclusive.
+//! An advisory lock covers the whole row, so between mounts a lock on any part of a file conflicts with a
+//! lock on any other part; within a mount the byte ranges are tracked here and only overlapping locks
+//! conflict.
+//!
+//! A blocking lock which can't be had straight away is retried by a thread of its own, which replies once
+//! it gets the lock, so the rest of the filesystem carries on in the meantime.
+
+use crate::Inode;
+use crate::pool::ConnectionPool;
+use fuser::ReplyEmpty;
+use libc::{EAGAIN, EINTR, EIO, F_UNLCK, F_WRLCK};
+use postgres::Client;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How often a blocking lock request tries again
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The end of a lock which extends to the end of the file
+const OFFSET_MAX: u64 = i64::MAX as u64;
+
+/// Identifies a file's advisory lock: the table and row id
+#[derive(Clone, Debug)]
+pub struct LockKey {
+    pub table: String,
+    pub id: i32,
+}
+
+/// A lock on a byte range of a file (inclusive at both ends)
+#[derive(Clone, Debug)]
+pub struct RangeLock {
+    pub owner: u64,
+    pub start: u64,
+    pub end: u64,
+    pub typ: i32,
+    pub pid: u32,
+}
+
+impl RangeLock {
+    fn overlaps(&self, other: &RangeLock) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+
+    fn conflicts(&self, other: &RangeLock) -> bool {
+        self.owner != other.owner && self.overlaps(other) && (self.typ == F_WRLCK || other.typ == F_WRLCK)
+    }
+}
+
+/// Locks held on one file, and the connection holding its advisory lock
+struct FileLocks {
+    key: LockKey,
+    ranges: Vec<RangeLock>,
+    client: Option<Client>,
+    exclusive: bool,
+}
+
+impl FileLocks {
+    /// Take (or upgrade to) the advisory lock needed, without waiting. Returns whether it is held.
+    fn acquire(&mut self, pool: &mut ConnectionPool, exclusive: bool) -> Result<bool, postgres::Error> {
+        if self.client.is_some() && (self.exclusive || !exclusive) {
+            return Ok(true);
+        }
+        let held_shared = self.client.is_some();
+        let mut client = match self.client.take() {
+            Some(client) => client,
+            None => pool.get()?,
+        };
+        let function = if exclusive { "pg_try_advisory_lock" } else { "pg_try_advisory_lock_shared" };
+        let query = format!("select {}(hashtext($1), $2)", function);
+        let got: bool = client.query_one(query.as_str(), &[&self.key.table, &self.key.id])?.get(0);
+        if got && held_shared {
+            client.execute("select pg_advisory_unlock_shared(hashtext($1), $2)", &[&self.key.table, &self.key.id])?;
+        }
+        if got || held_shared {
+            if got && exclusive {
+                self.exclusive = true;
+            }
+            self.client = Some(client);
+        } else {
+            pool.put(client);
+        }
+        Ok(got)
+    }
+
+    /// Let go of the advisory lock once nothing holds a lock on the file
+    fn release_if_unused(&mut self, pool: &mut ConnectionPool) {
+        if self.ranges.is_empty() && let Some(mut client) = self.client.take() {
+            match client.execute("select pg_advisory_unlock_all()", &[]) {
+                Ok(_) => pool.put(client),
+                Err(e) => log::error!("Unable to release advisory lock on {}/{}: {}", self.key.table, self.key.id, e),
+            }
+            self.exclusive = false;
+        }
+    }
+
+    /// Remove an owner's locks from a range, splitting any which only partly overlap it
+    fn unlock(&mut self, owner: u64, start: u64, end: u64) {
+        let mut remaining = vec![];
+        for range in self.ranges.drain(..) {
+            if range.owner != owner || range.end < start || range.start > end {
+                remaining.push(range);
+                continue;
+            }
+            if range.start < start {
+                remaining.push(RangeLock { end: start - 1, ..range.clone() });
+            }
+            if range.end > end {
+                remaining.push(RangeLock { start: end + 1, ..range });
+            }
+        }
+        self.ranges = remaining;
+    }
+}
+
+/// See whether an advisory lock could be taken on a connection, without keeping it
+fn probe(client: &mut Client, key: &LockKey, exclusive: bool) -> Result<bool, postgres::Error> {
+    let (lock, unlock) = match exclusive {
+        true => ("pg_try_advisory_lock", "pg_advisory_unlock"),
+        false => ("pg_try_advisory_lock_shared", "pg_advisory_unlock_shared"),
+    };
+    let got: bool = client.query_one(format!("select {}(hashtext($1), $2)", lock).as_str(), &[&key.table, &key.id])?.get(0);
+    if got {
+        client.execute(format!("select {}(hashtext($1), $2)", unlock).as_str(), &[&key.table, &key.id])?;
+    }
+    Ok(got)
+}
+
+fn probe_spare(pool: &mut ConnectionPool, key: &LockKey, exclusive: bool) -> Result<bool, postgres::Error> {
+    let mut client = pool.get()?;
+    let free = probe(&mut client, key, exclusive);
+    pool.put(client);
+    free
+}
+
+#[derive(Default)]
+struct LockState {
+    files: HashMap<Inode, FileLocks>,
+    ///blocking requests still waiting for a lock, by inode and lock owner
+    waiting: HashSet<(Inode, u64)>,
+}
+
+impl LockState {
+    /// Take a lock if nothing conflicts with it, returning whether it was taken
+    fn try_lock(&mut self, pool: &mut ConnectionPool, ino: Inode, key: &LockKey, lock: &RangeLock) -> Result<bool, i32> {
+        let file = self.files.entry(ino).or_insert_with(|| FileLocks {
+            key: key.clone(),
+            ranges: vec![],
+            client: None,
+            exclusive: false,
+        });
+        if file.ranges.iter().any(|range| range.conflicts(lock)) {
+            return Ok(false);
+        }
+        let exclusive = lock.typ == F_WRLCK || file.ranges.iter().any(|range| range.typ == F_WRLCK);
+        let got = file.acquire(pool, exclusive).map_err(|e| {
+            log::error!("Unable to take advisory lock on {}/{}: {}", key.table, key.id, e);
+            EIO
+        });
+        if got != Ok(true) {
+            file.release_if_unused(pool);
+            if file.client.is_none() {
+                self.files.remove(&ino);
+            }
+            return got;
+        }
+        //a new lock replaces any the owner already has on the same range
+        file.unlock(lock.owner, lock.start, lock.end);
+        file.ranges.push(lock.clone());
+        Ok(true)
+    }
+
+    fn unlock(&mut self, pool: &mut ConnectionPool, ino: Inode, owner: u64, start: u64, end: u64) {
+        if let Some(file) = self.files.get_mut(&ino) {
+            file.unlock(owner, start, end);
+            file.release_if_unused(pool);
+            if file.client.is_none() {
+                self.files.remove(&ino);
+            }
+        }
+    }
+}
+
+/// All the locks held through this mount
+#[derive(Clone)]
+pub struct LockTable {
+    state: Arc<Mutex<(LockState, ConnectionPool)>>,
+}
+
+impl LockTable {
+    pub fn new(connection_string: &str) -> LockTable {
+        LockTable {
+            state: Arc::new(Mutex::new((LockState::default(), ConnectionPool::new(connection_string)))),
+        }
+    }
+
+    /// Find a lock which would stop `lock` being taken, if there is one. Locks held through other mounts
+    /// are reported as covering the whole file, with no pid.
+    pub fn test(&self, ino: Inode, key: &LockKey, lock: &RangeLock) -> Result<Option<RangeLock>, i32> {
+        let mut guard = self.state.lock().unwrap();
+        let (state, pool) = &mut *guard;
+        let exclusive = lock.typ == F_WRLCK;
+        let free = match state.files.get_mut(&ino) {
+            Some(file) => {
+                if let Some(conflict) = file.ranges.iter().find(|range| range.conflicts(lock)) {
+                    return Ok(Some(conflict.clone()));
+                }
+                match file.client.as_mut() {
+                    Some(_) if file.exclusive || !exclusive => Ok(true),
+                    //only another mount can stop a shared lock being upgraded
+                    Some(client) => probe(client, key, exclusive),
+                    None => probe_spare(pool, key, exclusive),
+                }
+            }
+            None => probe_spare(pool, key, exclusive),
+        };
+        match free {
+            Ok(true) => Ok(None),
+            Ok(false) => Ok(Some(RangeLock { owner: 0, start: 0, end: OFFSET_MAX, typ: F_WRLCK, pid: 0 })),
+            Err(e) => {
+                log::error!("Unable to test advisory lock on {}/{}: {}", key.table, key.id, e);
+                Err(EIO)
+            }
+        }
+    }
+
+    /// Take, change or (with F_UNLCK) remove a lock without waiting. Fails with EAGAIN if it conflicts
+    /// with a lock held by someone else.
+    pub fn set(&self, ino: Inode, key: &LockKey, lock: &RangeLock) -> Result<(), i32> {
+        let mut guard = self.state.lock().unwrap();
+        let (state, pool) = &mut *guard;
+        if lock.typ == F_UNLCK {
+            state.unlock(pool, ino, lock.owner, lock.start, lock.end);
+            return Ok(());
+        }
+        match state.try_lock(pool, ino, key, lock)? {
+            true => Ok(()),
+            false => Err(EAGAIN),
+        }
+    }
+
+    /// Take a lock, replying once it has been taken. If it can't be taken straight away, a thread keeps
+    /// trying until it can, or until the owner's locks on the file are released.
+    pub fn set_and_wait(&self, ino: Inode, key: LockKey, lock: RangeLock, reply: ReplyEmpty) {
+        match self.set(ino, &key, &lock) {
+            Ok(()) => return reply.ok(),
+            Err(EAGAIN) => {}
+            Err(e) => return reply.error(e),
+        }
+        self.state.lock().unwrap().0.waiting.insert((ino, lock.owner));
+        let state = self.state.clone();
+        thread::spawn(move || loop {
+            thread::sleep(RETRY_INTERVAL);
+            let mut guard = state.lock().unwrap();
+            let (state, pool) = &mut *guard;
+            if !state.waiting.contains(&(ino, lock.owner)) {
+                return reply.error(EINTR);
+            }
+            match state.try_lock(pool, ino, &key, &lock) {
+                Ok(false) => continue,
+                Ok(true) => reply.ok(),
+                Err(e) => reply.error(e),
+            }
+            state.waiting.remove(&(ino, lock.owner));
+            return;
+        });
+    }
+
+    /// Release every lock an owner holds on a file, and give up waiting for any it has asked for
+    pub fn release_owner(&self, ino: Inode, owner: u64) {
+        let mut guard = self.state.lock().unwrap();
+        let (state, pool) = &mut *guard;
+        state.waiting.remove(&(ino, owner));
+        state.unlock(pool, ino, owner, 0, OFFSET_MAX);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc::F_RDLCK;
+
+    fn lock(owner: u64, start: u64, end: u64, typ: i32) -> RangeLock {
+        RangeLock { owner, start, end, typ, pid: 0 }
+    }
+
+    fn file(ranges: Vec<RangeLock>) -> FileLocks {
+        FileLocks { key: LockKey { table: "files".to_string(), id: 1 }, ranges, client: None, exclusive: false }
+    }
+
+    fn spans(file: &FileLocks) -> Vec<(u64, u64, u64)> {
+        let mut spans: Vec<(u64, u64, u64)> = file.ranges.iter().map(|range| (range.owner, range.start, range.end)).collect();
+        spans.sort();
+        spans
+    }
+
+    #[test]
+    fn unlock_middle_splits_range() {
+        let mut file = file(vec![lock(1, 0, 99, F_WRLCK)]);
+        file.unlock(1, 10, 19);
+        assert_eq!(spans(&file), vec![(1, 0, 9), (1, 20, 99)]);
+    }
+
+    #[test]
+    fn unlock_ends_trims_range() {
+        let mut file = file(vec![lock(1, 10, 19, F_WRLCK)]);
+        file.unlock(1, 0, 12);
+        assert_eq!(spans(&file), vec![(1, 13, 19)]);
+        file.unlock(1, 17, OFFSET_MAX);
+        assert_eq!(spans(&file), vec![(1, 13, 16)]);
+    }
+
+    #[test]
+    fn unlock_covering_removes_range() {
+        let mut file = file(vec![lock(1, 10, 19, F_WRLCK), lock(1, 30, 39, F_RDLCK)]);
+        file.unlock(1, 0, OFFSET_MAX);
+        assert!(file.ranges.is_empty());
+    }
+
+    #[test]
+    fn unlock_leaves_other_owners_and_ranges() {
+        let mut file = file(vec![lock(1, 0, 9, F_RDLCK), lock(2, 0, 9, F_RDLCK), lock(1, 20, 29, F_RDLCK)]);
+        file.unlock(1, 5, 15);
+        assert_eq!(spans(&file), vec![(1, 0, 4), (1, 20, 29), (2, 0, 9)]);
+    }
+
+    #[test]
+    fn split_keeps_lock_type() {
+        let mut file = file(vec![lock(1, 0, 99, F_RDLCK)]);
+        file.unlock(1, 50, 50);
+        assert!(file.ranges.iter().all(|range| range.typ == F_RDLCK));
+    }
+
+    #[test]
+    fn conflicts_need_overlap_a_write_lock_and_another_owner() {
+        assert!(lock(1, 0, 9, F_WRLCK).conflicts(&lock(2, 9, 19, F_RDLCK)));
+        assert!(!lock(1, 0, 9, F_WRLCK).conflicts(&lock(2, 10, 19, F_WRLCK)));
+        assert!(!lock(1, 0, 9, F_RDLCK).conflicts(&lock(2, 0, 9, F_RDLCK)));
+        assert!(!lock(1, 0, 9, F_WRLCK).conflicts(&lock(1, 0, 9, F_WRLCK)));
+    }
+}
@@ -29,24 +29,64 @@
 //! for production systems, or enable write access to data you are not prepared to see corrupted.
 //! Warnings aside, if you configure read only mode, and set up a Postgres user with only read permissions
 //! your data should be safe, and this implementation is single threaded and blocking, so should not
-//! exhaust your resources easily.
+//! exhaust your resources easily. Setting `async_backend = true` reads file content on a small tokio runtime
+//! instead, pipelining queries on one extra connection (and buffered writes on another).
 //!
 //! Bug reports, bug fixes, pull requests, examples, feature requests and any other feedback is welcome.
 //! If you try this out and have 5 minutes to drop me a quick message to tell me what you think that would
 //! be great.
 
+mod audit;
+mod block_cache;
+mod changes;
+mod channels;
 mod config;
-
-use libc::{ ENOSYS, ENOENT, ENODATA, EIO,  EROFS, EPERM, EISDIR};
+mod function;
+mod history;
+mod locks;
+mod permissions;
+mod pipeline;
+mod pool;
+mod quota;
+mod roles;
+mod staging;
+mod statements;
+mod snapshot;
+mod temporal;
+mod transaction;
+mod trash;
+mod write_buffer;
+mod xattr;
+
+use libc::{ ENOSYS, ENOENT, ENODATA, EIO,  EROFS, EACCES, EPERM, ESTALE, EISDIR, ENOTDIR, EBADF, O_ACCMODE, O_RDONLY, O_WRONLY, O_TRUNC, O_APPEND, O_NONBLOCK, POLLIN, POLLOUT, F_UNLCK, EEXIST, EINVAL, ENOTSUP, XATTR_CREATE, XATTR_REPLACE};
 use fuser::*;
 use postgres::{Client, NoTls};
+use postgres::types::ToSql;
 use std::ffi::{OsStr, OsString};
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::{env, cmp};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use bimap::BiMap;
-use crate::config::PgfsConfig;
-use std::time::SystemTime;
+use crate::audit::Caller;
+use crate::block_cache::{BlockCache, CacheConfig};
+use crate::pipeline::Pipeline;
+use crate::staging::{StageConfig, Staged};
+use crate::statements::Statements;
+use crate::write_buffer::{BufferConfig, WriteBuffers};
+use crate::changes::{ChangeFeed, RowIndex, RowLocation};
+use crate::roles::Roles;
+use crate::channels::ChannelHub;
+use crate::config::{PgfsConfig, TableConfig};
+use crate::function::FunctionArgs;
+use crate::history::HistoryNode;
+use crate::locks::{LockKey, LockTable, RangeLock};
+use crate::permissions::{Ownership, Privileges, PRIVILEGE_TTL};
+use crate::pool::ConnectionPool;
+use crate::quota::Quotas;
+use crate::temporal::Temporal;
+use crate::transaction::FileTransaction;
+use std::time::{Duration, Instant, SystemTime};
 use std::cmp::max;
 
 #[allow(dead_code)]
@@ -59,10 +99,82 @@ struct ByteaFileSystem {
     table_dir_inodes: BiMap<Inode, String>,
     file_inodes: HashMap<Inode, (Table, PgId)>,
     entries: BiMap<ChildNode, Inode>,
-    cache: HashMap<Inode, (i64, Vec<u8>)>,
+    write_buffers: WriteBuffers,
+    ///errors writing out buffers, to report on the file's next flush
+    write_errors: HashMap<Inode, i32>,
+    ///content of files being written whole, for tables with that write strategy
+    staged: HashMap<Inode, Staged>,
+    stage_config: StageConfig,
+    open_files: HashMap<u64, OpenFile>,
+    next_fh: u64,
+    channel_dir: Option<ChannelDir>,
+    rows: RowIndex,
+    change_feed: Option<ChangeFeed>,
+    pool: ConnectionPool,
+    ///statements prepared on the main connection
+    statements: Statements,
+    ///write transactions, for files in tables with write_transactions set which are open for writing
+    transactions: HashMap<Inode, FileTransaction>,
+    ///row version of files open for writing in tables with optimistic locking, as of when they were opened
+    /// or last written
+    versions: HashMap<Inode, i64>,
+    ///None when locks are left to the kernel, with `file_locks = false`
+    locks: Option<LockTable>,
+    ///directories and versions under the `.versions` directory of tables with history
+    history_nodes: HashMap<Inode, HistoryNode>,
+    ///files opened for writing whose content is still to be saved to history, with the writer's uid
+    unsaved_history: HashMap<Inode, u32>,
+    ///`.trash` directory of each table with soft delete, by table directory inode
+    trash_dirs: BiMap<Inode, Inode>,
+    ///id of the snapshot the main connection reads from, for a snapshot mount
+    snapshot: Option<String>,
+    ///`@<timestamp>` directories showing temporal tables as they were at that time
+    as_of_dirs: BiMap<Inode, String>,
+    ///the `@<timestamp>` directories, least recently looked up first
+    as_of_order: VecDeque<Inode>,
+    audit_table: Option<String>,
+    ///bytes written to each file since its writes were last recorded in the audit table, and who wrote them
+    unaudited_writes: HashMap<Inode, (Caller, i64)>,
+    roles: Option<Roles>,
+    ///the role the main connection is using, None for the connection user
+    db_role: Option<String>,
+    ///uid and gid of everything which doesn't belong to a table
+    owner: (u32, u32),
+    ///privileges by table and role, and when they were looked up
+    privileges: HashMap<(String, Option<String>), (Instant, Privileges)>,
+    ///mode and owner of files whose rows have them
+    ownership: HashMap<Inode, Ownership>,
+    quotas: Quotas,
+    block_cache: BlockCache,
+    ///the async backend, if it is enabled
+    pipeline: Option<Pipeline>,
+}
+
+/// The directory of LISTEN/NOTIFY channel files
+struct ChannelDir {
+    inode: Inode,
+    name: String,
+    hub: ChannelHub,
+    ///channel name for each channel file
+    channel_inodes: HashMap<Inode, String>,
+}
+
+/// State for an open file handle
+struct OpenFile {
+    ino: Inode,
+    ///for function backed files, the content returned by the read function (or being written), fetched
+    /// on first use so that all reads through this handle see the same snapshot
+    content: Option<Vec<u8>>,
+    ///opened for writing
+    writable: bool,
+    ///content has been written through this handle and not yet passed to the write function
+    dirty: bool,
+    ///the role of the user who opened it, for requests the kernel makes without the user's credentials
+    role: Option<String>,
 }
 
 impl ByteaFileSystem {
+    #[allow(clippy::unnecessary_cast)]
     fn dir_file_attr(inode: Inode) -> FileAttr {
         FileAttr {
             ino: inode as u64,
@@ -75,13 +187,15 @@ impl ByteaFileSystem {
             kind: FileType::Directory,
             perm: 0o755,
             nlink: 3,
-            uid: 1001,
-            gid: 20,
+            //owner and permissions are filled in by presented_attr
+            uid: 0,
+            gid: 0,
             rdev: 0,
             flags: 0,
             blksize: 512 * 1024,
         }
     }
+    #[allow(clippy::unnecessary_cast)]
     fn file_attr(inode: Inode, size: u64, ctime:Option<SystemTime>, mtime:Option<SystemTime>) -> FileAttr {
         FileAttr {
             ino: inode as u64,
@@ -92,16 +206,18 @@ impl ByteaFileSystem {
             ctime: ctime.unwrap_or(std::time::UNIX_EPOCH),
             crtime: std::time::UNIX_EPOCH,
             kind: FileType::RegularFile,
-            perm: 0o755,
+            //narrowed to the file's mode by presented_attr
+            perm: 0o777,
             nlink: 3,
-            uid: 1001,
-            gid: 20,
+            uid: 0,
+            gid: 0,
             rdev: 0,
             flags: 0,
             blksize: 512 * 1024,
         }
     }
-    pub fn new(name_: &str, db_client: Client, tables: Vec<Table>) -> ByteaFileSystem {
+    #[allow(clippy::needless_range_loop)]
+    pub fn new(name_: &str, db_client: Client, connection_string: &str, tables: Vec<Table>) -> ByteaFileSystem {
 
         //root dir will have inode 1, so we create tables from 2
         let mut file_attrs = HashMap::new();
@@ -111,7 +227,7 @@ impl ByteaFileSystem {
             dir_inodes.insert((i + 2) as Inode, tables[i].table_name.clone());
         }
 
-        ByteaFileSystem {
+        let mut filesystem = ByteaFileSystem {
             name: name_.to_string().parse().unwrap(),
             next_inode: (tables.len() + 1) as Inode,
             db_client,
@@ -120,9 +236,846 @@ impl ByteaFileSystem {
             table_dir_inodes: dir_inodes,
             entries: BiMap::new(),
             file_inodes: HashMap::new(),
-            cache: HashMap::new(),
+            write_buffers: WriteBuffers::new(BufferConfig::default()),
+            write_errors: HashMap::new(),
+            staged: HashMap::new(),
+            stage_config: StageConfig::default(),
+            open_files: HashMap::new(),
+            next_fh: 0,
+            channel_dir: None,
+            rows: RowIndex::default(),
+            change_feed: None,
+            pool: ConnectionPool::new(connection_string),
+            statements: Statements::new(false),
+            transactions: HashMap::new(),
+            versions: HashMap::new(),
+            locks: Some(LockTable::new(connection_string)),
+            history_nodes: HashMap::new(),
+            unsaved_history: HashMap::new(),
+            trash_dirs: BiMap::new(),
+            snapshot: None,
+            as_of_dirs: BiMap::new(),
+            as_of_order: VecDeque::new(),
+            audit_table: None,
+            unaudited_writes: HashMap::new(),
+            roles: None,
+            db_role: None,
+            owner: (0, 0),
+            privileges: HashMap::new(),
+            ownership: HashMap::new(),
+            quotas: Quotas::new(None),
+            block_cache: BlockCache::new(CacheConfig::default()),
+            pipeline: None,
+        };
+        filesystem.add_history_dirs();
+        filesystem.add_trash_dirs();
+        filesystem
+    }
+
+    /// Give the `.versions` directory of each table with history an inode
+    fn add_history_dirs(&mut self) {
+        let table_inodes: Vec<Inode> = self.table_dir_inodes.iter()
+            .filter(|(_, name)| self.tables.get(*name).is_some_and(|table| table.history_table.is_some()))
+            .map(|(inode, _)| *inode)
+            .collect();
+        for table_inode in table_inodes {
+            self.history_entry(table_inode, history::VERSIONS_DIR, HistoryNode::Files { table_inode });
+        }
+    }
+
+    /// Give the `.trash` directory of each table with soft delete an inode
+    fn add_trash_dirs(&mut self) {
+        let table_inodes: Vec<Inode> = self.table_dir_inodes.iter()
+            .filter(|(_, name)| self.tables.get(*name).is_some_and(|table| table.deleted_field.is_some()))
+            .map(|(inode, _)| *inode)
+            .collect();
+        for table_inode in table_inodes {
+            self.next_inode += 1;
+            let inode = self.next_inode;
+            self.entries.insert(ChildNode { parent: table_inode, name: trash::TRASH_DIR.to_string() }, inode);
+            let mut attr = ByteaFileSystem::dir_file_attr(inode);
+            attr.perm = 0o555;
+            self.inode_file_attrs.insert(inode, attr);
+            self.trash_dirs.insert(table_inode, inode);
+        }
+    }
+
+    /// List the deleted files of a table, giving any new ones an inode. Files in the trash are keyed by
+    /// the trash directory's inode rather than the table's.
+    fn list_trash(&mut self, trash_inode: Inode) -> Result<Vec<(Inode, String)>, i32> {
+        let table_inode = *self.trash_dirs.get_by_right(&trash_inode).ok_or(ENOENT)?;
+        let table = self.table_dir_inodes.get_by_left(&table_inode).and_then(|name| self.tables.get(name)).cloned().ok_or(ENOENT)?;
+        let query = table.trash_query_string.as_deref().ok_or(ENOENT)?;
+        let rows = self.statements.query(&mut self.db_client, query, &[]).map_err(|e| {
+            dbg!(e);
+            EIO
+        })?;
+        let mut listing = vec![];
+        for row in rows {
+            let name: String = row.get("name");
+            let (size, ctime, mtime) = table.row_attr_values(&row);
+            let pgid = PgId { table_inode: trash_inode, pg_id: row.get::<&str, i32>("id") as u64 };
+            let existing = self.rows.lock().unwrap().get(&pgid).map(|location| location.inode);
+            let inode = match existing {
+                Some(inode) => {
+                    self.inode_file_attrs.insert(inode, ByteaFileSystem::file_attr(inode, size, ctime, mtime));
+                    inode
+                }
+                None => self.add_file_entry(table.clone(), pgid, name.clone(), size, ctime, mtime),
+            };
+            self.ownership.insert(inode, table.row_ownership(&row));
+            if let Some(attr) = self.inode_file_attrs.get_mut(&inode) {
+                attr.perm = 0o444;
+            }
+            listing.push((inode, name));
+        }
+        Ok(listing)
+    }
+
+    /// Move a file between a table's directory and its trash, keeping its inode as the kernel expects
+    fn move_file_entry(&mut self, ino: Inode, parent: Inode, name: String) {
+        let Some((_, pgid)) = self.file_inodes.get_mut(&ino) else {
+            return;
+        };
+        let old_pgid = *pgid;
+        pgid.table_inode = parent;
+        let new_pgid = *pgid;
+        {
+            let mut rows = self.rows.lock().unwrap();
+            rows.remove(&old_pgid);
+            rows.insert(new_pgid, RowLocation { inode: ino, name: name.clone() });
+        }
+        self.entries.remove_by_right(&ino);
+        self.entries.insert(ChildNode { parent, name }, ino);
+        let read_only = self.trash_dirs.contains_right(&parent);
+        if let Some(attr) = self.inode_file_attrs.get_mut(&ino) {
+            attr.perm = if read_only { 0o444 } else { 0o777 };
+        }
+    }
+
+    fn audit_target(&self, ino: Inode) -> Option<audit::Target> {
+        let (table, pgid) = self.file_inodes.get(&ino)?;
+        Some(audit::Target {
+            table: table.table_name.clone(),
+            row_id: pgid.pg_id as i32,
+            name: self.entries.get_by_right(&ino).map(|child| child.name.clone()).unwrap_or_default(),
+        })
+    }
+
+    /// Record a change to a file in the audit table, if there is one
+    fn audit(&mut self, caller: &Caller, operation: &str, ino: Inode, bytes: Option<i64>) {
+        let target = self.audit_target(ino);
+        self.record_audit(caller, operation, ino, target, bytes);
+    }
+
+    /// Record a change in the audit table, on the file's transaction if it has one. If the record can't be
+    /// written, the transaction is rolled back so that there are no unrecorded changes.
+    fn record_audit(&mut self, caller: &Caller, operation: &str, ino: Inode, target: Option<audit::Target>, bytes: Option<i64>) {
+        let (Some(audit_table), Some(target)) = (self.audit_table.clone(), target) else {
+            return;
+        };
+        let (client, statements) = self.connection_for(ino);
+        if let Err(e) = audit::record(client, statements, &audit_table, operation, caller, &target, bytes) {
+            log::error!("Unable to record {} of {}/{} in the audit table: {}", operation, target.table, target.name, e);
+            if let Some(transaction) = self.transactions.get_mut(&ino) {
+                transaction.failed = true;
+            }
+        }
+    }
+
+    /// Count bytes written to a file, to be recorded when the writes are committed
+    fn note_write(&mut self, caller: Caller, ino: Inode, bytes: usize) {
+        if self.audit_table.is_some() {
+            let written = self.unaudited_writes.entry(ino).or_insert((caller, 0));
+            *written = (caller, written.1 + bytes as i64);
+        }
+    }
+
+    fn audit_writes(&mut self, ino: Inode) {
+        if let Some((caller, bytes)) = self.unaudited_writes.remove(&ino) {
+            self.audit(&caller, "write", ino, Some(bytes));
+        }
+    }
+
+    /// Value of an extended attribute of a file
+    fn get_xattr(&mut self, ino: Inode, name: &OsStr) -> Result<Vec<u8>, i32> {
+        let (table, pgid) = self.file_inodes.get(&ino).cloned().ok_or(ENODATA)?;
+        let source = table.xattr_source();
+        let attribute = source.attribute(name.to_str().unwrap_or("")).ok_or(ENODATA)?;
+        let (client, statements) = self.connection_for(ino);
+        let value = source.get(client, statements, |query| table.scoped_query(query), pgid.pg_id as i32, &attribute)
+            .map_err(xattr_error)?;
+        value.map(|value| value.into_bytes()).ok_or(ENODATA)
+    }
+
+    /// Names of the extended attributes a file has, each followed by a nul, as listxattr returns them
+    fn list_xattr(&mut self, ino: Inode) -> Result<Vec<u8>, i32> {
+        let Some((table, pgid)) = self.file_inodes.get(&ino).cloned() else {
+            return Ok(vec![]);
+        };
+        let (client, statements) = self.connection_for(ino);
+        let names = table.xattr_source().list(client, statements, |query| table.scoped_query(query), pgid.pg_id as i32)
+            .map_err(xattr_error)?;
+        Ok(names.into_iter().flat_map(|name| name.into_bytes().into_iter().chain([0])).collect())
+    }
+
+    /// Set an extended attribute of a file, or remove it if there is no value
+    fn set_xattr(&mut self, caller: &Caller, ino: Inode, name: &OsStr, value: Option<&[u8]>, flags: i32) -> Result<(), i32> {
+        let (table, pgid) = self.file_inodes.get(&ino).cloned().ok_or(ENOTSUP)?;
+        let source = table.xattr_source();
+        let attribute = source.attribute(name.to_str().unwrap_or("")).ok_or(ENOTSUP)?;
+        if self.read_only_file(ino) {
+            return Err(EROFS);
+        }
+        let value = value.map(|value| std::str::from_utf8(value).map_err(|_| EINVAL)).transpose()?;
+        if value.is_none() || flags & (XATTR_CREATE | XATTR_REPLACE) != 0 {
+            let (client, statements) = self.connection_for(ino);
+            let existing = source.get(client, statements, |query| table.scoped_query(query), pgid.pg_id as i32, &attribute)
+                .map_err(xattr_error)?;
+            if flags & XATTR_CREATE != 0 && existing.is_some() {
+                return Err(EEXIST);
+            }
+            if existing.is_none() && (value.is_none() || flags & XATTR_REPLACE != 0) {
+                return Err(ENODATA);
+            }
+        }
+        let assignment = source.assignment(&attribute, value.is_some());
+        match value {
+            Some(value) => self.update_row(ino, &assignment, &[&value])?,
+            None => self.update_row(ino, &assignment, &[])?,
+        }
+        self.audit(caller, if value.is_some() { "setxattr" } else { "removexattr" }, ino, None);
+        Ok(())
+    }
+
+    /// Directories where nothing can be created, deleted or renamed
+    fn read_only_dir(&self, ino: Inode) -> bool {
+        self.history_nodes.contains_key(&ino)
+            || self.as_of_dirs.contains_left(&ino)
+            || self.table_dir_inodes.get_by_left(&ino).and_then(|key| self.tables.get(key)).is_some_and(|table| table.read_only)
+    }
+
+    /// Files which can't be written
+    fn read_only_file(&self, ino: Inode) -> bool {
+        self.history_nodes.contains_key(&ino)
+            || self.in_trash(ino)
+            || self.file_inodes.get(&ino).is_some_and(|(table, _)| table.read_only)
+    }
+
+    /// The `@<timestamp>` directory for a time, adding it (with a directory for each temporal table) the
+    /// first time it is looked up
+    fn as_of_dir(&mut self, timestamp: &str) -> Result<Inode, i32> {
+        if let Some(inode) = self.as_of_dirs.get_by_right(timestamp).copied() {
+            self.as_of_order.retain(|used| *used != inode);
+            self.as_of_order.push_back(inode);
+            return Ok(inode);
+        }
+        if let Err(e) = temporal::check_timestamp(&mut self.db_client, &mut self.statements, timestamp) {
+            dbg!(e);
+            return Err(ENOENT);
+        }
+        while self.as_of_order.len() >= temporal::AS_OF_DIRS {
+            self.remove_as_of_dir();
+        }
+        let as_of_tables: Vec<Table> = self.tables.values()
+            .filter(|table| table.as_of.is_none())
+            .filter_map(|table| table.as_of(timestamp))
+            .collect();
+        self.next_inode += 1;
+        let dir_inode = self.next_inode;
+        let mut attr = ByteaFileSystem::dir_file_attr(dir_inode);
+        attr.perm = 0o555;
+        self.inode_file_attrs.insert(dir_inode, attr);
+        self.as_of_dirs.insert(dir_inode, timestamp.to_string());
+        self.as_of_order.push_back(dir_inode);
+        for table in as_of_tables {
+            self.next_inode += 1;
+            let inode = self.next_inode;
+            //tables are keyed by directory name, which is unique as names can't contain '/'
+            let key = format!("@{}/{}", timestamp, table.table_name);
+            self.inode_file_attrs.insert(inode, attr);
+            self.inode_file_attrs.get_mut(&inode).unwrap().ino = inode;
+            self.entries.insert(ChildNode { parent: dir_inode, name: table.table_name.clone() }, inode);
+            self.table_dir_inodes.insert(inode, key.clone());
+            self.tables.insert(key, table);
+        }
+        Ok(dir_inode)
+    }
+
+    /// Forget the `@<timestamp>` directory looked up least recently, with its tables and their files
+    fn remove_as_of_dir(&mut self) {
+        let Some(dir_inode) = self.as_of_order.pop_front() else {
+            return;
+        };
+        self.as_of_dirs.remove_by_left(&dir_inode);
+        self.inode_file_attrs.remove(&dir_inode);
+        let table_inodes: Vec<Inode> = self.entries.iter()
+            .filter(|(child, _)| child.parent == dir_inode)
+            .map(|(_, inode)| *inode)
+            .collect();
+        for table_inode in table_inodes {
+            self.entries.remove_by_right(&table_inode);
+            self.inode_file_attrs.remove(&table_inode);
+            if let Some((_, key)) = self.table_dir_inodes.remove_by_left(&table_inode) {
+                self.tables.remove(&key);
+            }
+            let files: Vec<Inode> = self.file_inodes.iter()
+                .filter(|(_, (_, pgid))| pgid.table_inode == table_inode)
+                .map(|(inode, _)| *inode)
+                .collect();
+            for inode in files {
+                self.remove_file_entry(inode);
+            }
+        }
+    }
+
+    fn in_trash(&self, ino: Inode) -> bool {
+        self.file_inodes.get(&ino).is_some_and(|(_, pgid)| self.trash_dirs.contains_right(&pgid.table_inode))
+    }
+
+    /// Delete a file's row, or move it to the trash if its table has soft delete. Files already in the
+    /// trash, or deleted with `purge`, are deleted for good.
+    fn delete_file(&mut self, ino: Inode, purge: bool) -> Result<(), i32> {
+        let Some((table, pgid)) = self.file_inodes.get(&ino).cloned() else {
+            dbg!("missing file inode");
+            return Ok(());
+        };
+        let id = pgid.pg_id as i32;
+        //rows in the trash aren't counted against the quota already
+        let counted = !self.in_trash(ino);
+        let size = self.inode_file_attrs.get(&ino).map_or(0, |attr| attr.size);
+        let result = if table.deleted_field.is_some() && !purge && counted {
+            let (client, statements) = self.connection_for(ino);
+            trash::soft_delete(client, statements, &table, id)
+        } else {
+            let delete_query_string = table.delete_query_string.clone()
+                .unwrap_or_else(|| format!("delete from {} where {} = $1", table.table_name, table.id_field));
+            let (client, statements) = self.connection_for(ino);
+            statements.execute(client, delete_query_string.as_str(), &[&id])
+        };
+        //if there is something in the cache, then we are deleting a file
+        //which has not been fully written. Add a config (default true)
+        //to flush first
+        self.remove_file_entry(ino);
+        if result.is_ok() && counted {
+            self.quotas.file_removed(&table.table_name, size);
+        }
+        result.map(|_| ()).map_err(|e| {
+            let errno = roles::errno(&e, EIO);
+            dbg!("Failed to delete: ", e);
+            errno
+        })
+    }
+
+    /// The inode for something under `.versions`, adding it if it is new
+    fn history_entry(&mut self, parent: Inode, name: &str, node: HistoryNode) -> Inode {
+        let child = ChildNode { parent, name: name.to_string() };
+        if let Some(inode) = self.entries.get_by_left(&child) {
+            return *inode;
+        }
+        self.next_inode += 1;
+        let inode = self.next_inode;
+        self.entries.insert(child, inode);
+        self.history_nodes.insert(inode, node);
+        let mut attr = ByteaFileSystem::dir_file_attr(inode);
+        attr.perm = 0o555;
+        self.inode_file_attrs.insert(inode, attr);
+        inode
+    }
+
+    /// List a directory under `.versions`, giving anything new an inode
+    fn list_history_dir(&mut self, ino: Inode) -> Result<Vec<(Inode, FileType, String)>, i32> {
+        let node = *self.history_nodes.get(&ino).ok_or(ENOENT)?;
+        let table = self.table_dir_inodes.get_by_left(&node.table_inode()).and_then(|name| self.tables.get(name)).cloned().ok_or(ENOENT)?;
+        let source = table.history_source().ok_or(ENOENT)?;
+        let mut listing = vec![];
+        match node {
+            HistoryNode::Files { table_inode } => {
+                let files = history::files(&mut self.db_client, &mut self.statements, &source).map_err(|e| {
+                    dbg!(e);
+                    EIO
+                })?;
+                for (file_id, name) in files {
+                    let inode = self.history_entry(ino, &name, HistoryNode::File { table_inode, file_id });
+                    listing.push((inode, FileType::Directory, name));
+                }
+            }
+            HistoryNode::File { table_inode, file_id } => {
+                let versions = history::versions(&mut self.db_client, &mut self.statements, &source, file_id).map_err(|e| {
+                    dbg!(e);
+                    EIO
+                })?;
+                for version in versions {
+                    let inode = self.history_entry(ino, &version.name, HistoryNode::Version { table_inode, version_id: version.id });
+                    let mut attr = ByteaFileSystem::file_attr(inode, version.size, Some(version.saved_at), Some(version.saved_at));
+                    attr.perm = 0o444;
+                    self.inode_file_attrs.insert(inode, attr);
+                    listing.push((inode, FileType::RegularFile, version.name));
+                }
+            }
+            HistoryNode::Version { .. } => return Err(ENOTDIR),
+        }
+        Ok(listing)
+    }
+
+    /// Copy the content of a file to its history table before it is first changed after being opened
+    /// for writing
+    fn save_history(&mut self, ino: Inode) -> Result<(), i32> {
+        let Some(uid) = self.unsaved_history.get(&ino).copied() else {
+            return Ok(());
+        };
+        let Some((table, pgid)) = self.file_inodes.get(&ino).cloned() else {
+            return Ok(());
+        };
+        let (client, statements) = self.connection_for(ino);
+        if let Some(source) = table.history_source()
+            && let Err(e) = history::save(client, statements, &source, pgid.pg_id as i32, uid) {
+            dbg!(e);
+            return Err(EIO);
+        }
+        self.unsaved_history.remove(&ino);
+        Ok(())
+    }
+
+    /// Record changes made through the mount in a table
+    pub fn enable_audit(&mut self, audit_table: &str) {
+        self.audit_table = Some(audit_table.to_string());
+    }
+
+    /// Read everything from a snapshot, which the main connection is already in a transaction for
+    pub fn use_snapshot(&mut self, id: String) {
+        self.snapshot = Some(id);
+    }
+
+    /// In a snapshot mount, recover the snapshot transaction from an earlier failed statement
+    fn reset_snapshot(&mut self) {
+        if self.snapshot.is_some() && self.statements.take_failed() {
+            snapshot::reset(&mut self.db_client);
+            //rolling back to the savepoint also undoes any SET ROLE since
+            self.db_role = None;
+        }
+    }
+
+    /// Make requests as the PostgreSQL role mapped to each caller's uid
+    pub fn enable_roles(&mut self, roles: Roles) {
+        self.roles = Some(roles);
+    }
+
+    /// Owner of the root directory and anything else which doesn't belong to a table
+    pub fn set_owner(&mut self, uid: u32, gid: u32) {
+        self.owner = (uid, gid);
+    }
+
+    /// The table an inode belongs to: a file's table, or the table a directory shows
+    fn inode_table(&self, ino: Inode) -> Option<&Table> {
+        match self.file_inodes.get(&ino) {
+            Some((table, _)) => Some(table),
+            None => self.table_dir_inodes.get_by_left(&ino).and_then(|name| self.tables.get(name)),
+        }
+    }
+
+    /// How long the kernel may keep an inode's attributes. Nothing about a file is kept with roles, as the
+    /// kernel shares what it keeps between users whose roles may not all see the file.
+    fn attr_ttl(&self, ino: Inode) -> Duration {
+        match self.file_inodes.get(&ino) {
+            Some(_) if self.roles.is_some() => Duration::ZERO,
+            _ => self.inode_table(ino).map_or(TTL, |table| table.attr_ttl),
+        }
+    }
+
+    /// How long the kernel may keep an entry naming an inode. fuser sends the same timeout for the entry and
+    /// the attributes that come with it, so this is the shorter of the two.
+    fn entry_ttl(&self, ino: Inode) -> Duration {
+        match self.file_inodes.get(&ino) {
+            Some(_) if self.roles.is_some() => Duration::ZERO,
+            _ => self.inode_table(ino).map_or(TTL, |table| table.entry_ttl.min(table.attr_ttl)),
+        }
+    }
+
+    /// Flags for opening a file, for how its table wants the page cache used
+    fn cache_flags(&self, ino: Inode) -> u32 {
+        match self.file_inodes.get(&ino) {
+            Some((table, _)) if table.direct_io => consts::FOPEN_DIRECT_IO,
+            Some((table, _)) if table.keep_cache => consts::FOPEN_KEEP_CACHE,
+            _ => 0,
         }
     }
+
+    /// The current role's privileges on a table, looked up again once they are PRIVILEGE_TTL old
+    fn privileges(&mut self, table_name: &str, data_field: &str) -> Privileges {
+        let key = (table_name.to_string(), self.db_role.clone());
+        if let Some((checked, privileges)) = self.privileges.get(&key)
+            && checked.elapsed() < PRIVILEGE_TTL {
+            return *privileges;
+        }
+        let privileges = permissions::privileges(&mut self.db_client, &mut self.statements, table_name, data_field).unwrap_or_else(|e| {
+            log::warn!("Unable to look up privileges on {}: {}", table_name, e);
+            Privileges::ALL
+        });
+        self.privileges.insert(key, (Instant::now(), privileges));
+        privileges
+    }
+
+    /// Attributes as shown to the caller: owned by the table's uid and gid, with permission bits from the
+    /// table's mode and what the current role may do
+    fn presented_attr(&mut self, attr: &FileAttr) -> FileAttr {
+        let mut attr = *attr;
+        let Some(table) = self.inode_table(attr.ino) else {
+            (attr.uid, attr.gid) = self.owner;
+            return attr;
+        };
+        let ownership = self.ownership.get(&attr.ino).copied().unwrap_or_default();
+        attr.uid = ownership.uid.unwrap_or(table.uid);
+        attr.gid = ownership.gid.unwrap_or(table.gid);
+        let (mode, read_only) = (ownership.mode.unwrap_or(table.mode), table.read_only);
+        let function_writable = table.is_function_backed().then(|| table.write_function_string.is_some());
+        let (table_name, data_field) = (table.table_name.clone(), table.bytea_field.clone());
+        let mut privileges = self.privileges(&table_name, &data_field);
+        if let Some(writable) = function_writable {
+            //function backed files are written by the write function, not an UPDATE
+            privileges.write = writable;
+        }
+        if read_only {
+            privileges.write = false;
+            privileges.create = false;
+        }
+        attr.perm = match attr.kind {
+            FileType::Directory => permissions::dir_perm(attr.perm, mode, &privileges),
+            _ => permissions::file_perm(attr.perm, mode, &privileges),
+        };
+        attr
+    }
+
+    /// Set the limits on buffering writes
+    pub fn set_write_cache(&mut self, config: BufferConfig) {
+        self.write_buffers = WriteBuffers::new(config);
+    }
+
+    /// Set where files being written whole are staged
+    pub fn set_staging(&mut self, config: StageConfig) {
+        self.stage_config = config;
+    }
+
+    /// Size the cache of blocks read from files
+    pub fn set_read_cache(&mut self, config: CacheConfig) {
+        self.block_cache = BlockCache::new(config);
+    }
+
+    /// Read file content and write out buffered ranges through the async backend
+    pub fn enable_async_backend(&mut self, pipeline: Pipeline) {
+        self.pipeline = Some(pipeline);
+    }
+
+    /// Limit the space the database can take up, in bytes
+    pub fn set_capacity(&mut self, capacity: u64) {
+        self.quotas = Quotas::new(Some(capacity));
+    }
+
+    /// Use the statements the connection was set up with, which know whether to send statements unprepared
+    pub fn use_statements(&mut self, statements: Statements) {
+        self.statements = statements;
+    }
+
+    /// Leave locks to the kernel, so they only apply within this mount
+    pub fn disable_locks(&mut self) {
+        self.locks = None;
+    }
+
+    /// Check that there is room for a file to grow to `size`, before writing it
+    fn make_room(&mut self, ino: Inode, size: u64) -> Result<(), i32> {
+        let Some((table, _)) = self.file_inodes.get(&ino) else {
+            return Ok(());
+        };
+        let growth = size.saturating_sub(self.inode_file_attrs.get(&ino).map_or(0, |attr| attr.size));
+        let tables = table_names(&self.tables);
+        self.quotas.grow(&mut self.db_client, &mut self.statements, &tables, &table.quota(), growth)
+    }
+
+    /// Stop counting the space a file no longer uses once it has been truncated to `size`
+    fn release_room(&mut self, ino: Inode, size: u64) {
+        let Some((table, _)) = self.file_inodes.get(&ino) else {
+            return;
+        };
+        let shrinkage = self.inode_file_attrs.get(&ino).map_or(0, |attr| attr.size).saturating_sub(size);
+        self.quotas.shrink(&table.table_name, shrinkage);
+    }
+
+    /// Get the main connection ready for a request from a process
+    fn start_request(&mut self, req: &Request<'_>) -> Result<(), i32> {
+        self.reset_snapshot();
+        let role = match &self.roles {
+            Some(roles) => roles.role_for(req.uid()).map(|role| role.to_string()),
+            None => return Ok(()),
+        };
+        self.use_role(role)
+    }
+
+    /// With roles, check that the caller's role can see a file's row, as the files listed may have been
+    /// read by another role which sees more of the table
+    fn check_visible(&mut self, ino: Inode) -> Result<(), i32> {
+        if self.roles.is_none() {
+            return Ok(());
+        }
+        let Some((table, pgid)) = self.file_inodes.get(&ino) else {
+            return Ok(());
+        };
+        let query = format!("select 1 from ({}) as pgfs_row where id = $1", table.query_string.trim_end().trim_end_matches(';'));
+        let id = pgid.pg_id as i32;
+        match self.statements.query_opt(&mut self.db_client, query.as_str(), &[&id]) {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => Err(ENOENT),
+            Err(e) => {
+                log::warn!("Unable to check row {} of {}: {}", id, table.table_name, e);
+                Err(roles::errno(&e, EIO))
+            }
+        }
+    }
+
+    /// Check that a process may chmod or chown a file, where its table keeps them: only root can give a
+    /// file to another user or group, and only its owner or root can change its mode
+    fn check_owner_change(&self, caller: u32, ino: Inode, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>) -> Result<(), i32> {
+        let Some((table, _)) = self.file_inodes.get(&ino) else {
+            return Ok(());
+        };
+        if caller == 0 {
+            return Ok(());
+        }
+        let ownership = self.ownership.get(&ino).copied().unwrap_or_default();
+        let (owner, group) = (ownership.uid.unwrap_or(table.uid), ownership.gid.unwrap_or(table.gid));
+        let chown = (table.owner_field.is_some() && uid.is_some_and(|uid| uid != owner))
+            || (table.group_field.is_some() && gid.is_some_and(|gid| gid != group));
+        let chmod = mode.is_some() && table.mode_field.is_some();
+        if chown || (chmod && caller != owner) {
+            return Err(EPERM);
+        }
+        Ok(())
+    }
+
+    /// Switch the main connection to a role, if it isn't using it already
+    fn use_role(&mut self, role: Option<String>) -> Result<(), i32> {
+        if self.roles.is_none() || role == self.db_role {
+            return Ok(());
+        }
+        if let Err(e) = roles::set_role(&mut self.db_client, role.as_deref()) {
+            log::warn!("Unable to switch to role {:?}: {}", role, e);
+            self.statements.mark_failed();
+            return Err(EACCES);
+        }
+        self.db_role = role;
+        Ok(())
+    }
+
+    /// Keep up to date with changes made by other applications
+    pub fn enable_change_feed(&mut self, change_feed: ChangeFeed) {
+        self.change_feed = Some(change_feed);
+    }
+
+    /// Add a directory at the top level with a file for each LISTEN/NOTIFY channel
+    pub fn enable_channels(&mut self, directory: &str, names: &[String], hub: ChannelHub) {
+        self.next_inode += 1;
+        let dir_inode = self.next_inode;
+        self.inode_file_attrs.insert(dir_inode, ByteaFileSystem::dir_file_attr(dir_inode));
+        self.channel_dir = Some(ChannelDir {
+            inode: dir_inode,
+            name: directory.to_string(),
+            hub,
+            channel_inodes: HashMap::new(),
+        });
+        for name in names {
+            self.add_channel(name);
+        }
+    }
+
+    fn add_channel(&mut self, name: &str) -> Inode {
+        self.next_inode += 1;
+        let inode = self.next_inode;
+        let channel_dir = self.channel_dir.as_mut().unwrap();
+        channel_dir.channel_inodes.insert(inode, name.to_string());
+        channel_dir.hub.listen(name);
+        self.entries.insert(ChildNode { parent: channel_dir.inode, name: name.to_string() }, inode);
+        self.inode_file_attrs.insert(inode, ByteaFileSystem::file_attr(inode, 0, None, None));
+        inode
+    }
+
+    /// Give a row an inode and add it to the directory for its table
+    fn add_file_entry(&mut self, table: Table, pgid: PgId, name: String, size: u64, ctime: Option<SystemTime>, mtime: Option<SystemTime>) -> Inode {
+        self.next_inode += 1;
+        let inode = self.next_inode;
+        self.inode_file_attrs.insert(inode, ByteaFileSystem::file_attr(inode, size, ctime, mtime));
+        self.rows.lock().unwrap().insert(pgid, RowLocation { inode, name: name.clone() });
+        self.file_inodes.insert(inode, (table, pgid));
+        self.entries.insert(ChildNode { parent: pgid.table_inode, name }, inode);
+        inode
+    }
+
+    fn remove_file_entry(&mut self, ino: Inode) {
+        self.entries.remove_by_right(&ino);
+        if let Some((_, pgid)) = self.file_inodes.remove(&ino) {
+            self.rows.lock().unwrap().remove(&pgid);
+        }
+        self.inode_file_attrs.remove(&ino);
+        self.ownership.remove(&ino);
+        self.write_buffers.take(ino);
+        self.write_errors.remove(&ino);
+        self.staged.remove(&ino);
+        self.block_cache.invalidate(ino);
+    }
+
+    /// Bring the maps up to date with changes made by other applications, if the change feed is enabled
+    fn apply_changes(&mut self) {
+        let Some(change_feed) = self.change_feed.as_ref() else {
+            return;
+        };
+        for change in change_feed.take_changes() {
+            dbg!(&change);
+            if let Some(table_inode) = self.table_dir_inodes.get_by_right(&change.table) {
+                self.refresh_row(*table_inode, change.id);
+            }
+        }
+    }
+
+    /// Reload a single row, adding, updating or removing its file
+    fn refresh_row(&mut self, table_inode: Inode, id: u64) {
+        let Some(table) = self.table_dir_inodes.get_by_left(&table_inode).and_then(|name| self.tables.get(name)).cloned() else {
+            return;
+        };
+        let pgid = PgId { table_inode, pg_id: id };
+        let existing = self.rows.lock().unwrap().get(&pgid).map(|location| location.inode);
+        let query = format!("select * from ({}) as pgfs_row where id = $1", table.query_string.trim_end().trim_end_matches(';'));
+        match self.statements.query_opt(&mut self.db_client, query.as_str(), &[&(id as i32)]) {
+            Ok(Some(row)) => {
+                let name: String = row.get("name");
+                let (size, ctime, mtime) = table.row_attr_values(&row);
+                match existing {
+                    Some(inode) => {
+                        self.block_cache.invalidate(inode);
+                        self.entries.remove_by_right(&inode);
+                        self.entries.insert(ChildNode { parent: table_inode, name: name.clone() }, inode);
+                        self.rows.lock().unwrap().insert(pgid, RowLocation { inode, name });
+                        //keep the size of anything still being written through the cache
+                        if !self.write_buffers.contains(inode) && !self.staged.contains_key(&inode) {
+                            self.inode_file_attrs.insert(inode, ByteaFileSystem::file_attr(inode, size, ctime, mtime));
+                        }
+                        self.ownership.insert(inode, table.row_ownership(&row));
+                    }
+                    None => {
+                        let inode = self.add_file_entry(table.clone(), pgid, name, size, ctime, mtime);
+                        self.ownership.insert(inode, table.row_ownership(&row));
+                    }
+                }
+            }
+            Ok(None) => {
+                if let Some(inode) = existing {
+                    self.remove_file_entry(inode);
+                }
+            }
+            Err(e) => {
+                dbg!(e);
+            }
+        }
+    }
+
+    /// The advisory lock key for a file - its table and row id, or its channel name
+    fn lock_key(&self, ino: Inode) -> Option<LockKey> {
+        if let Some((table, pgid)) = self.file_inodes.get(&ino) {
+            return Some(LockKey { table: table.table_name.clone(), id: pgid.pg_id as i32 });
+        }
+        let channel_dir = self.channel_dir.as_ref()?;
+        channel_dir.channel_inodes.get(&ino).map(|channel| LockKey { table: format!("{}/{}", channel_dir.name, channel), id: 0 })
+    }
+
+    /// Entries of a directory after `offset`, as (inode, offset, kind, name), and whether that is all of
+    /// them. Table directories are listed a page of rows at a time, so callers carry on from the offset of
+    /// the last entry until it is.
+    fn list_dir(&mut self, ino: Inode, offset: i64) -> Result<(Vec<DirEntry>, bool), i32> {
+        let entries: Vec<(Inode, FileType, String)> = if ino == 1 {
+            //@<timestamp> directories aren't listed, only looked up
+            let mut entries: Vec<(Inode, FileType, String)> = self.table_dir_inodes.iter()
+                .filter(|(_, key)| self.tables.get(*key).is_some_and(|tab| &tab.table_name == *key))
+                .map(|(inode, key)| (*inode, FileType::Directory, key.clone()))
+                .collect();
+            if let Some(channel_dir) = self.channel_dir.as_ref() {
+                entries.push((channel_dir.inode, FileType::Directory, channel_dir.name.clone()));
+            }
+            entries
+        } else if let Some(channel_dir) = self.channel_dir.as_ref()
+            && channel_dir.inode == ino {
+            channel_dir.channel_inodes.iter().map(|(inode, name)| (*inode, FileType::RegularFile, name.clone())).collect()
+        } else if self.as_of_dirs.contains_left(&ino) {
+            self.entries.iter()
+                .filter(|(child, _)| child.parent == ino)
+                .map(|(child, inode)| (*inode, FileType::Directory, child.name.clone()))
+                .collect()
+        } else if self.trash_dirs.contains_right(&ino) {
+            self.list_trash(ino)?.into_iter().map(|(inode, name)| (inode, FileType::RegularFile, name)).collect()
+        } else if self.history_nodes.contains_key(&ino) {
+            self.list_history_dir(ino)?
+        } else if let Some(table_name) = self.table_dir_inodes.get_by_left(&ino).cloned() {
+            return self.list_table_dir(ino, &table_name, offset);
+        } else {
+            return Err(ENOENT);
+        };
+        let listing = [(ino, FileType::Directory, ".".to_string()), (1, FileType::Directory, "..".to_string())].into_iter()
+            .chain(entries)
+            .enumerate()
+            .skip(offset as usize)
+            // i + 1 means the index of the next entry
+            .map(|(i, (inode, kind, name))| (inode, i as i64 + 1, kind, name))
+            .collect();
+        Ok((listing, true))
+    }
+
+    /// `.`, `..`, `.versions` and `.trash` where the table has them, then a page of its rows after `offset`
+    fn list_table_dir(&mut self, ino: Inode, table_name: &str, offset: i64) -> Result<(Vec<DirEntry>, bool), i32> {
+        let mut listing = vec![];
+        if offset == 0 {
+            listing.push((ino, 1, FileType::Directory, ".".to_string()));
+        }
+        if offset <= 1 {
+            listing.push((ino, 2, FileType::Directory, "..".to_string()));
+        }
+        let mut fixed_entries = 2;
+        for special_dir in [history::VERSIONS_DIR, trash::TRASH_DIR] {
+            if let Some(inode) = self.entries.get_by_left(&ChildNode { parent: ino, name: special_dir.to_string() }) {
+                fixed_entries += 1;
+                if offset < fixed_entries {
+                    listing.push((*inode, fixed_entries, FileType::Directory, special_dir.to_string()));
+                }
+            }
+        }
+        let Some(table) = self.tables.get(table_name).cloned() else {
+            return Ok((listing, true));
+        };
+        //carry on after the row the last reply ended with
+        let after = (offset - fixed_entries - 1).max(-1) + i32::MIN as i64;
+        let rows = self.statements.query(&mut self.db_client, table.page_query().as_str(), &[&after]).map_err(|e| {
+            let errno = roles::errno(&e, EIO);
+            dbg!(e);
+            errno
+        })?;
+        let complete = rows.len() < LISTING_PAGE;
+        for row in rows {
+            let id = row.get::<&str, i32>("id");
+            let child = ChildNode { parent: ino, name: row.get("name") };
+            dbg!("add {}", &child.name);
+            let inode = match self.entries.get_by_left(&child) {
+                Some(inode) => *inode,
+                None => {
+                    let (size, ctime, mtime) = table.row_attr_values(&row);
+                    let pgid = PgId { table_inode: ino, pg_id: id as u64 };
+                    let inode = self.add_file_entry(table.clone(), pgid, child.name.clone(), size, ctime, mtime);
+                    self.ownership.insert(inode, table.row_ownership(&row));
+                    inode
+                }
+            };
+            listing.push((inode, row_offset(fixed_entries, id), FileType::RegularFile, child.name));
+        }
+        Ok((listing, complete))
+    }
+
+    fn channel_name(&self, ino: Inode) -> Option<&String> {
+        self.channel_dir.as_ref().and_then(|dir| dir.channel_inodes.get(&ino))
+    }
     /*pub fn get_next_inode(&mut self) -> Inode {
         self.next_inode += 1;
         self.next_inode
@@ -131,104 +1084,541 @@ impl ByteaFileSystem {
     pub fn flush_internal(&mut self, ino: Inode) {
         self.write_data_to_postgres(ino, None);
     }*/
+    #[allow(clippy::collapsible_if, clippy::unnecessary_unwrap)]
     fn create_internal(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr) -> Result<Inode,i32 > {
         dbg!("create_internal");
+        self.start_request(_req)?;
         //create the file
         //get parent to know what table it is
         if parent == 1 {
             //can't create a file at the top level
             return Err(ENOENT);
         }
+        if self.read_only_dir(parent) || self.trash_dirs.contains_right(&parent) {
+            return Err(EROFS);
+        }
+        if let Some(channel_dir) = self.channel_dir.as_ref()
+            && channel_dir.inode == parent {
+            let name = name.to_str().ok_or(ENOENT)?;
+            return Ok(self.add_channel(name));
+        }
 
         if let Some(table_name) = self.table_dir_inodes.get_by_left(&parent) {
             if let Some(table) = self.tables.get(table_name) {
                 if table.read_only {
                     return Err(EROFS)
                 }
-                self.next_inode += 1;
-                let inode = self.next_inode;
+                self.quotas.check_new_file(&mut self.db_client, &mut self.statements, &table.quota())?;
                 //insert the new record into the db with no data (create is basicly touch)
                 let name = name.to_str().unwrap();
                 let query = format!("insert into {} ({}) values ($1) returning {}", table_name, table.name_field.as_ref().unwrap(), &table.id_field);
-                let id = self.db_client.query_one(query.as_str(), &[&name, ]);
+                let id = self.statements.query_opt(&mut self.db_client, query.as_str(), &[&name, ]);
                 if id.is_err() {
-                    dbg!(id.err().unwrap());
-                    return Err(ENOSYS);
-                } else {
-                    let id = id.unwrap().get::<usize, i32>(0) as u64;
-                    self.file_inodes.insert(inode, (table.clone(), PgId {
+                    let e = id.err().unwrap();
+                    let errno = roles::errno(&e, ENOSYS);
+                    dbg!(e);
+                    return Err(errno);
+                } else if let Some(row) = id.unwrap() {
+                    self.quotas.file_added(table_name, 0);
+                    let id = row.get::<usize, i32>(0) as u64;
+                    let pgid = PgId {
                         table_inode: parent,
                         pg_id: id,
-                    }));
-                    self.entries.insert(ChildNode { parent, name: name.to_string() }, inode);
-                    self.inode_file_attrs.insert(inode, ByteaFileSystem::file_attr(inode, 0, Some(SystemTime::now()), Some(SystemTime::now())));
+                    };
+                    let inode = self.add_file_entry(table.clone(), pgid, name.to_string(), 0, Some(SystemTime::now()), Some(SystemTime::now()));
+                    self.audit(&Caller::of(_req), "create", inode, Some(0));
                     return Ok(inode);
                 }
             }
         }
         Err(ENOSYS)
     }
-    fn write_data_to_postgres(&mut self, ino:u64, data: Option<(i64, &[u8])> ) -> bool {
-        //get cached data and add any optional data
-        if let Some((table, pgid)) =  self.file_inodes.get(&ino) {
-            let  query_string = format!("update {} set {} = coalesce(overlay({} placing $1 from $2 for $3), $1) where {} = $4", table.table_name, table.bytea_field, table.bytea_field, table.id_field);
-            //let mut data_to_write: Option<(i64, &[u8])> = None;
-            let mut new_len = None;
-            if self.cache.contains_key(&ino) {
-                let (offset, mut cached_data) = self.cache.remove(&ino).unwrap();
-                if data.is_some() {
-                    if offset as usize + cached_data.len() == data.as_ref().unwrap().0 as usize {
-                        //existing data precedes new data
-                        cached_data.extend_from_slice(data.unwrap().1);
-                        //data_to_write = Some((offset, cached_data.as_slice()));
-                        if let Err(e) = self.db_client.execute(query_string.as_str(), &[&cached_data, &(offset as i32+1), &(cached_data.len() as i32), &(pgid.pg_id as i32)]) {
-                            dbg!(e);
-                            return false
-                        } else {
-                            new_len = Some(offset + cached_data.len() as i64);
-                        }
-                    } else {
-                        //write the cached data then the new data
-                        if let Err(e) = self.db_client.execute(query_string.as_str(), &[&cached_data, &(offset as i32+1), &(cached_data.len() as i32), &(pgid.pg_id as i32)]) {
-                            dbg!(e);
-                            return false
-                        } else {
-                            new_len = Some(offset as i64 + new_len.unwrap_or(0) + cached_data.len() as i64);
-                            let data = data.unwrap();
-                            if let Err(e) = self.db_client.execute(query_string.as_str(), &[&data.1, &(data.0 as i32 +1), &(data.1.len() as i32), &(pgid.pg_id as i32)]) {
-                                dbg!(e);
-                            } else {
-                                new_len = Some(offset as i64 + new_len.unwrap_or(0) + cached_data.len() as i64);
-                            }
-                        }
-                    }
-                } else {
-                    if let Err(e) = self.db_client.execute(query_string.as_str(), &[&cached_data, &(offset as i32+1), &(cached_data.len() as i32), &(pgid.pg_id as i32)]) {
-                        dbg!(e);
-                        return false
-                    } else {
-                        new_len = Some((offset as i64 + cached_data.len() as i64) as i64);
-                    }
-                }
-            } else if data.is_some(){
-                let (offset, data) = data.unwrap();
-                if let Err(e) = self.db_client.execute(query_string.as_str(), &[&data, &(offset as i32+1), &(data.len() as i32), &(pgid.pg_id as i32)]) {
-                    dbg!(e);
-                    return false
-                } else {
-                    new_len = Some(data.len() as i64);
+    /// Write out a file's buffer for a flush, fsync or close, which reports (and so clears) any earlier
+    /// failure to write it out
+    fn write_out(&mut self, ino: Inode) -> Result<(), i32> {
+        let result = self.write_data_to_postgres(ino, None);
+        self.write_errors.remove(&ino);
+        result
+    }
+    fn write_data_to_postgres(&mut self, ino:u64, data: Option<(i64, &[u8])> ) -> Result<(), i32> {
+        let result = self.write_data_to_client(ino, data);
+        if result.is_err() && let Some(transaction) = self.transactions.get_mut(&ino) {
+            transaction.failed = true;
+        }
+        result
+    }
+    fn write_data_to_client(&mut self, ino:u64, data: Option<(i64, &[u8])> ) -> Result<(), i32> {
+        //kept until a flush, fsync or close reports it
+        if let Some(e) = self.write_errors.get(&ino) {
+            return Err(*e);
+        }
+        //write out any buffered data first, as the new data may overwrite it
+        if let Some(ranges) = self.write_buffers.take(ino) {
+            let ranges: Vec<(u64, Vec<u8>)> = ranges.into_ranges().collect();
+            if ranges.len() > 1 && self.can_pipeline_writes(ino) {
+                self.overlay_pipelined(ino, &ranges)?;
+            } else {
+                for (offset, buffered) in ranges {
+                    self.overlay(ino, offset as i64, &buffered)?;
                 }
             }
-            //length is max of offset + new data and existing length
-            if let Some(attrs) = self.inode_file_attrs.get_mut(&ino) {
-                if let Some(new_len) = new_len {
-                    attrs.size = max(attrs.size, new_len as u64);
-                    attrs.blocks = (attrs.size+1)/(attrs.blksize as u64)
+        }
+        if let Some((offset, data)) = data {
+            self.overlay(ino, offset, data)?;
+        }
+        Ok(())
+    }
+
+    /// Write data into a file at an offset, extending the file if needed
+    fn overlay(&mut self, ino: Inode, offset: i64, data: &[u8]) -> Result<(), i32> {
+        let Some((table, _)) = self.file_inodes.get(&ino) else {
+            return Ok(());
+        };
+        let set_clause = overlay_clause(&table.bytea_field, "$1", "$2", "$3");
+        self.save_history(ino)?;
+        self.update_row(ino, &set_clause, &[&data, &(offset as i32 + 1), &(data.len() as i32)])?;
+        self.extend_to(ino, (offset + data.len() as i64) as u64);
+        Ok(())
+    }
+
+    /// Length is max of the end of new data and existing length
+    fn extend_to(&mut self, ino: Inode, end: u64) {
+        if let Some(attrs) = self.inode_file_attrs.get_mut(&ino) {
+            attrs.size = max(attrs.size, end);
+            attrs.blocks = (attrs.size+1)/(attrs.blksize as u64)
+        }
+    }
+
+    /// Copy `len` bytes of one file into another without them leaving the database, returning how many were
+    /// copied. Files whose content isn't just their row's data (or is staged or being written in a
+    /// transaction elsewhere) give ENOTSUP, so the kernel copies them through read and write instead.
+    fn copy_range(&mut self, ino_in: Inode, offset_in: u64, ino_out: Inode, offset_out: u64, len: u64) -> Result<u64, i32> {
+        let (src_table, src_id) = self.file_inodes.get(&ino_in).cloned().ok_or(ENOTSUP)?;
+        let (dst_table, dst_id) = self.file_inodes.get(&ino_out).cloned().ok_or(ENOTSUP)?;
+        if src_table.is_function_backed() || dst_table.is_function_backed() || self.staged.contains_key(&ino_in)
+            || self.staged.contains_key(&ino_out) || self.transactions.contains_key(&ino_in) {
+            return Err(ENOTSUP);
+        }
+        if self.transactions.get(&ino_out).is_some_and(|transaction| transaction.failed) {
+            return Err(EIO);
+        }
+        self.write_data_to_postgres(ino_in, None)?;
+        self.write_data_to_postgres(ino_out, None)?;
+        let size = |ino| self.inode_file_attrs.get(&ino).map_or(0, |attr| attr.size);
+        let (src_size, dst_size) = (size(ino_in), size(ino_out));
+        //the reply can only count up to u32::MAX, and the kernel asks again for the rest
+        let copied = len.min(src_size.saturating_sub(offset_in)).min(u32::MAX as u64);
+        if copied == 0 {
+            return Ok(0);
+        }
+        self.make_room(ino_out, offset_out + copied)?;
+        self.save_history(ino_out)?;
+        let whole_file = offset_in == 0 && offset_out == 0 && copied == src_size && dst_size <= copied
+            && src_table.temporal.is_none() && src_table.as_of.is_none() && dst_table.version_expr.is_none();
+        let result = if whole_file {
+            self.block_cache.invalidate(ino_out);
+            let query = format!("update {} as pgfs_dst set {} = pgfs_src.{} from {} as pgfs_src where pgfs_dst.{} = $1 and pgfs_src.{} = $2",
+                                dst_table.table_name, dst_table.bytea_field, src_table.bytea_field, src_table.table_name,
+                                dst_table.id_field, src_table.id_field);
+            let (client, statements) = self.connection_for(ino_out);
+            statements.execute(client, query.as_str(), &[&(dst_id.pg_id as i32), &(src_id.pg_id as i32)]).map(|_| ()).map_err(|e| {
+                let errno = roles::errno(&e, EIO);
+                dbg!(e);
+                errno
+            })
+        } else {
+            //the source's own data query, so it is read as it would be by a read, taking the source id, offset
+            // and length as $1 to $3
+            let source = format!("({})", src_table.data_query_string.trim_end().trim_end_matches(';'));
+            let set_clause = overlay_clause(&dst_table.bytea_field, &source, "$4", "$3");
+            self.update_row(ino_out, &set_clause, &[&(src_id.pg_id as i32), &(offset_in as i32 + 1), &(copied as i32), &(offset_out as i32 + 1)])
+        };
+        if let Err(e) = result {
+            if let Some(transaction) = self.transactions.get_mut(&ino_out) {
+                transaction.failed = true;
+            }
+            return Err(e);
+        }
+        self.extend_to(ino_out, offset_out + copied);
+        Ok(copied)
+    }
+
+    /// Whether a file's buffered ranges can be written out together by the async backend: not in a write
+    /// transaction, which has its own connection, nor with a version to check on each update
+    fn can_pipeline_writes(&self, ino: Inode) -> bool {
+        self.pipeline.is_some() && !self.transactions.contains_key(&ino)
+            && self.file_inodes.get(&ino).is_some_and(|(table, _)| table.version_expr.is_none())
+    }
+
+    /// Write several ranges of a file with their updates pipelined on the async backend's connection
+    fn overlay_pipelined(&mut self, ino: Inode, ranges: &[(u64, Vec<u8>)]) -> Result<(), i32> {
+        let (table, pgid) = self.file_inodes.get(&ino).cloned().ok_or(ENOENT)?;
+        self.save_history(ino)?;
+        self.block_cache.invalidate(ino);
+        let query = format!("update {} set {} where {} = $4", table.table_name, overlay_clause(&table.bytea_field, "$1", "$2", "$3"), table.id_field);
+        self.pipeline.as_ref().unwrap().overlay_all(&query, pgid.pg_id as i32, ranges).map_err(|e| {
+            let errno = roles::errno(&e, EIO);
+            dbg!(e);
+            errno
+        })?;
+        if let Some((offset, data)) = ranges.last() {
+            self.extend_to(ino, offset + data.len() as u64);
+        }
+        Ok(())
+    }
+
+    /// Start staging a file opened for writing, if its table writes files whole
+    fn stage_if_configured(&mut self, ino: Inode) {
+        if self.file_inodes.get(&ino).is_some_and(|(table, _)| table.whole_file && !table.is_function_backed()) {
+            self.staged.entry(ino).or_default();
+        }
+    }
+
+    /// Fetch the content of a staged file, as far as `size` or all of it, if it hasn't been already
+    fn load_staged(&mut self, ino: Inode, size: Option<u64>) -> Result<(), i32> {
+        if self.staged.get(&ino).is_none_or(|staged| staged.loaded()) {
+            return Ok(());
+        }
+        let (table, pgid) = self.file_inodes.get(&ino).cloned().ok_or(ENOENT)?;
+        let mut staged = self.staged.remove(&ino).unwrap();
+        let config = self.stage_config.clone();
+        let (client, statements) = self.connection_for(ino);
+        let result = staged.load(&config, size, |offset, length| {
+            statements.query_opt(client, table.data_query_string.as_str(), &[&(pgid.pg_id as i32), &(1 + offset as i32), &(length as i32)])
+                .map(|row| row.and_then(|row| row.get::<usize, Option<Vec<u8>>>(0)).unwrap_or_default())
+        });
+        self.staged.insert(ino, staged);
+        result.map_err(|e| {
+            log::warn!("Unable to stage {}/{}: {}", table.table_name, pgid.pg_id, e);
+            EIO
+        })
+    }
+
+    /// Write a staged file back with a single update, if it has changed
+    fn write_staged(&mut self, ino: Inode) -> Result<(), i32> {
+        let Some(mut staged) = self.staged.remove(&ino) else {
+            return Ok(());
+        };
+        let result = if staged.changed { self.update_staged(ino, &mut staged) } else { Ok(()) };
+        match result {
+            Ok(()) => staged.changed = false,
+            Err(_) => if let Some(transaction) = self.transactions.get_mut(&ino) {
+                transaction.failed = true;
+            }
+        }
+        self.staged.insert(ino, staged);
+        result
+    }
+
+    fn update_staged(&mut self, ino: Inode, staged: &mut Staged) -> Result<(), i32> {
+        let data_field = self.file_inodes.get(&ino).map(|(table, _)| table.bytea_field.clone()).ok_or(ENOENT)?;
+        self.save_history(ino)?;
+        if let Some(bytes) = staged.in_memory() {
+            return self.update_row(ino, &format!("{} = $1", data_field), &[&bytes]);
+        }
+        //too big to send as a parameter, so stream it to a temporary table first
+        staged.copy_to_server(self.client_for(ino)).map_err(|e| {
+            log::warn!("Unable to copy staged file to the server: {}", e);
+            EIO
+        })?;
+        let result = self.update_row(ino, &format!("{} = (select data from pg_temp.pgfs_staging)", data_field), &[]);
+        let _ = staging::clear_server(self.client_for(ino));
+        result
+    }
+
+    /// Update a file's row with `set_clause`, whose parameters are `params`. For tables with optimistic
+    /// locking, the update only happens if nothing else has changed the row since the file was opened, and
+    /// otherwise fails with ESTALE, or goes to a conflict copy of the file if the table is set up for that.
+    fn update_row(&mut self, ino: Inode, set_clause: &str, params: &[&(dyn ToSql + Sync)]) -> Result<(), i32> {
+        self.block_cache.invalidate(ino);
+        let result = match self.try_update_row(ino, set_clause, params) {
+            Err(ESTALE) if self.file_inodes.get(&ino).is_some_and(|(table, _)| table.conflict_copy) => {
+                self.move_to_conflict_copy(ino)?;
+                self.block_cache.invalidate(ino);
+                self.try_update_row(ino, set_clause, params)
+            }
+            result => result,
+        };
+        //a failed statement aborts the file's transaction (a conflict only means no row was updated)
+        if result.is_err_and(|e| e != ESTALE) && let Some(transaction) = self.transactions.get_mut(&ino) {
+            transaction.failed = true;
+        }
+        result
+    }
+
+    fn try_update_row(&mut self, ino: Inode, set_clause: &str, params: &[&(dyn ToSql + Sync)]) -> Result<(), i32> {
+        let (table, pgid) = self.file_inodes.get(&ino).cloned().ok_or(ENOENT)?;
+        let id = pgid.pg_id as i32;
+        let expected = self.versions.get(&ino).copied();
+        let mut query = format!("update {} set {}", table.table_name, set_clause);
+        if let Some(version_field) = table.version_field.as_ref() {
+            query.push_str(&format!(", {0} = coalesce({0}, 0) + 1", version_field));
+        }
+        let mut params = params.to_vec();
+        params.push(&id);
+        query.push_str(&format!(" where {} = ${}", table.id_field, params.len()));
+        let (Some(version_expr), Some(expected)) = (table.version_expr.as_ref(), expected.as_ref()) else {
+            let (client, statements) = self.connection_for(ino);
+            return statements.execute(client, query.as_str(), &params).map(|_| ()).map_err(|e| {
+                let errno = roles::errno(&e, EIO);
+                dbg!(e);
+                errno
+            });
+        };
+        params.push(expected);
+        query.push_str(&format!(" and {0} = ${1} returning {0}", version_expr, params.len()));
+        let (client, statements) = self.connection_for(ino);
+        match statements.query_opt(client, query.as_str(), &params) {
+            Ok(Some(row)) => {
+                self.versions.insert(ino, row.get(0));
+                Ok(())
+            }
+            Ok(None) => {
+                let name = self.entries.get_by_right(&ino).map(|child| child.name.as_str()).unwrap_or("");
+                log::warn!("{}/{} has been changed or deleted by someone else since it was opened, so it was not written",
+                    table.table_name, name);
+                Err(ESTALE)
+            }
+            Err(e) => {
+                let errno = roles::errno(&e, EIO);
+                dbg!(e);
+                Err(errno)
+            }
+        }
+    }
+
+    /// Note the version of a file's row when it is opened for writing, for tables with optimistic locking
+    fn record_version(&mut self, ino: Inode) -> Result<(), i32> {
+        let Some((table, pgid)) = self.file_inodes.get(&ino).cloned() else {
+            return Ok(());
+        };
+        let Some(version_expr) = table.version_expr.as_ref() else {
+            return Ok(());
+        };
+        let query = format!("select {} from {} where {} = $1", version_expr, table.table_name, table.id_field);
+        let (client, statements) = self.connection_for(ino);
+        match statements.query_opt(client, query.as_str(), &[&(pgid.pg_id as i32)]) {
+            Ok(Some(row)) => {
+                self.versions.insert(ino, row.get(0));
+                Ok(())
+            }
+            Ok(None) => Err(ENOENT),
+            Err(e) => {
+                dbg!(e);
+                Err(EIO)
+            }
+        }
+    }
+
+    fn record_version_if_new(&mut self, ino: Inode) -> Result<(), i32> {
+        if self.versions.contains_key(&ino) {
+            return Ok(());
+        }
+        self.record_version(ino)
+    }
+
+    /// Point a file whose row has been changed by someone else at a new row named
+    /// `<name>.conflict-<timestamp>`, starting with the current content of the original, so that what is
+    /// being written can be saved without overwriting the other change. The original reappears under
+    /// its own name.
+    fn move_to_conflict_copy(&mut self, ino: Inode) -> Result<(), i32> {
+        let (table, pgid) = self.file_inodes.get(&ino).cloned().ok_or(ENOENT)?;
+        let name = self.entries.get_by_right(&ino).map(|child| child.name.clone()).unwrap_or_default();
+        let conflict_name = format!("{}.conflict-{}", name, time::strftime("%Y%m%dT%H%M%SZ", &time::now_utc()).unwrap());
+        let query = format!("insert into {0} ({1}, {2}) values ($1, coalesce((select {2} from {0} where {3} = $2), ''::bytea)) \
+            returning {3}, {4}, octet_length({2})",
+            table.table_name, table.name_column(), table.bytea_field, table.id_field, table.version_expr.as_deref().unwrap_or("0::bigint"));
+        let (client, statements) = self.connection_for(ino);
+        let row = statements.query_opt(client, query.as_str(), &[&conflict_name, &(pgid.pg_id as i32)]).map_err(|e| {
+            dbg!(e);
+            ESTALE
+        })?.ok_or(ESTALE)?;
+        log::warn!("Writing {}/{} to {} instead", table.table_name, name, conflict_name);
+        let copy = PgId { table_inode: pgid.table_inode, pg_id: row.get::<usize, i32>(0) as u64 };
+        self.versions.insert(ino, row.get(1));
+        let size = row.get::<usize, Option<i32>>(2).unwrap_or(0) as u64;
+        {
+            let mut rows = self.rows.lock().unwrap();
+            rows.remove(&pgid);
+            rows.insert(copy, RowLocation { inode: ino, name: conflict_name.clone() });
+        }
+        self.entries.remove_by_right(&ino);
+        self.entries.insert(ChildNode { parent: pgid.table_inode, name: conflict_name }, ino);
+        if let Some((_, file_pgid)) = self.file_inodes.get_mut(&ino) {
+            *file_pgid = copy;
+        }
+        if let Some(attrs) = self.inode_file_attrs.get_mut(&ino) {
+            attrs.size = max(attrs.size, size);
+        }
+        self.refresh_row(pgid.table_inode, pgid.pg_id);
+        Ok(())
+    }
+
+    /// The connection for changes to a file - its transaction's connection if it is being written in one
+    fn client_for(&mut self, ino: Inode) -> &mut Client {
+        self.connection_for(ino).0
+    }
+
+    /// The connection for changes to a file, as for `client_for`, with the statements prepared on it
+    fn connection_for(&mut self, ino: Inode) -> (&mut Client, &mut Statements) {
+        match self.transactions.get_mut(&ino) {
+            Some(transaction) => (&mut transaction.client, &mut transaction.statements),
+            None => (&mut self.db_client, &mut self.statements),
+        }
+    }
+
+    /// Start or join a write transaction for a file opened for writing, if its table uses them
+    fn begin_transaction_if_configured(&mut self, ino: Inode, fh: u64) -> Result<(), i32> {
+        match self.file_inodes.get(&ino) {
+            Some((table, _)) if table.write_transactions && !table.is_function_backed() => self.begin_transaction(ino, fh),
+            _ => Ok(()),
+        }
+    }
+
+    /// Start a write transaction for a file, or join the one already open for it
+    fn begin_transaction(&mut self, ino: Inode, fh: u64) -> Result<(), i32> {
+        if let Some(transaction) = self.transactions.get_mut(&ino) {
+            transaction.handles.insert(fh);
+            return Ok(());
+        }
+        let role = self.db_role.clone();
+        let statements = Statements::new(self.statements.unnamed());
+        let transaction = self.pool.get().and_then(|client| FileTransaction::begin(client, statements, fh, role)).map_err(|e| {
+            dbg!(e);
+            EIO
+        })?;
+        self.transactions.insert(ino, transaction);
+        Ok(())
+    }
+
+    /// Leave a file's write transaction, committing it (or rolling it back after a failed write) when
+    /// the last handle writing the file is released
+    fn end_transaction(&mut self, ino: Inode, fh: u64) -> Result<(), i32> {
+        let Some(transaction) = self.transactions.get_mut(&ino) else {
+            return Ok(());
+        };
+        transaction.handles.remove(&fh);
+        if !transaction.handles.is_empty() {
+            return Ok(());
+        }
+        //a failure here marks the transaction failed, so it is rolled back below
+        let written = self.write_out(ino);
+        let mut transaction = self.transactions.remove(&ino).unwrap();
+        //anything read while the transaction was open is from before it
+        self.block_cache.invalidate(ino);
+        match transaction.finish() {
+            Ok(true) => {
+                self.pool.put(transaction.client);
+                Ok(())
+            }
+            Ok(false) => {
+                dbg!("rolled back failed write", ino);
+                self.pool.put(transaction.client);
+                //the size we have is for the data which was rolled back
+                self.refresh_file(ino);
+                Err(written.err().unwrap_or(EIO))
+            }
+            Err(e) => {
+                dbg!(e);
+                self.refresh_file(ino);
+                Err(EIO)
+            }
+        }
+    }
+
+    /// Reload a file's attributes from the database
+    fn refresh_file(&mut self, ino: Inode) {
+        if let Some((_, pgid)) = self.file_inodes.get(&ino) {
+            let pgid = *pgid;
+            self.refresh_row(pgid.table_inode, pgid.pg_id);
+        }
+    }
+
+    /// Allocate a file handle. Handles opened with initial content (a new or truncated file) are dirty
+    fn open_handle(&mut self, ino: Inode, writable: bool, content: Option<Vec<u8>>) -> u64 {
+        self.next_fh += 1;
+        let dirty = content.is_some();
+        let role = self.db_role.clone();
+        self.open_files.insert(self.next_fh, OpenFile { ino, content, writable, dirty, role });
+        self.next_fh
+    }
+
+    /// Truncate a function backed file. The kernel doesn't always say which handle a truncate is for
+    /// (open with O_TRUNC sends it without one), so apply it to every handle open for writing, or call
+    /// the write function straight away if there are none.
+    fn truncate_function_content(&mut self, ino: Inode, fh: Option<u64>, size: u64) -> Result<(), i32> {
+        let mut handles: Vec<u64> = match fh {
+            Some(fh) => vec![fh],
+            None => self.open_files.iter().filter(|(_, f)| f.ino == ino && f.writable).map(|(fh, _)| *fh).collect(),
+        };
+        let temporary = handles.is_empty();
+        if temporary {
+            handles.push(self.open_handle(ino, true, None));
+        }
+        let mut result = Ok(());
+        for fh in handles {
+            if size == 0 {
+                //no need to call the read function just to throw the result away
+                self.open_files.get_mut(&fh).ok_or(EBADF)?.content = Some(Vec::new());
+            } else {
+                self.function_content(fh)?.resize(size as usize, 0);
+            }
+            self.open_files.get_mut(&fh).unwrap().dirty = true;
+            if temporary {
+                result = self.write_function_content(fh);
+                self.open_files.remove(&fh);
+            }
+        }
+        if let Some(attr) = self.inode_file_attrs.get_mut(&ino) {
+            attr.size = size;
+        }
+        result
+    }
+
+    /// Get the content of a function backed file for an open handle, calling the read function the first
+    /// time it is needed.
+    fn function_content(&mut self, fh: u64) -> Result<&mut Vec<u8>, i32> {
+        let open_file = self.open_files.get_mut(&fh).ok_or(EBADF)?;
+        if open_file.content.is_none() {
+            let (table, pgid) = self.file_inodes.get(&open_file.ino).ok_or(ENOENT)?;
+            let content = match table.read_function_string.as_ref() {
+                Some(query) => {
+                    let name = self.entries.get_by_right(&open_file.ino).map(|child| child.name.as_str()).unwrap_or("");
+                    let args = FunctionArgs { id: pgid.pg_id as i32, name, table: table.table_name.as_str() };
+                    function::read_content(&mut self.db_client, &mut self.statements, query, &table.function_params, &args).map_err(|e| {
+                        dbg!(e);
+                        EIO
+                    })?
                 }
+                None => Vec::new(),
+            };
+            if let Some(attr) = self.inode_file_attrs.get_mut(&open_file.ino) {
+                attr.size = content.len() as u64;
             }
+            open_file.content = Some(content);
         }
+        Ok(open_file.content.as_mut().unwrap())
+    }
 
-        true
+    /// Pass the content written through a handle to the write function, if anything has been written
+    fn write_function_content(&mut self, fh: u64) -> Result<(), i32> {
+        if let Some(open_file) = self.open_files.get_mut(&fh)
+            && open_file.dirty
+            && let Some((table, pgid)) = self.file_inodes.get(&open_file.ino)
+            && let Some(query) = table.write_function_string.as_ref() {
+            let content = open_file.content.as_deref().unwrap_or(&[]);
+            let name = self.entries.get_by_right(&open_file.ino).map(|child| child.name.as_str()).unwrap_or("");
+            let args = FunctionArgs { id: pgid.pg_id as i32, name, table: table.table_name.as_str() };
+            if let Err(e) = function::write_content(&mut self.db_client, &mut self.statements, query, &table.function_params, &args, content) {
+                dbg!(e);
+                return Err(EIO);
+            }
+            open_file.dirty = false;
+            if let Some(attr) = self.inode_file_attrs.get_mut(&open_file.ino) {
+                attr.size = content.len() as u64;
+            }
+        }
+        Ok(())
     }
 
 }
@@ -256,7 +1646,6 @@ struct ChildNode {
 struct Table {
     table_name: String,
     id_field: String,
-    length_field: String,
     bytea_field: String,
     name_field: Option<String>,
     query_string: String,
@@ -265,12 +1654,184 @@ struct Table {
     delete_query_string:Option<String>,
     created_field:Option<String>,
     modified_field:Option<String>,
+    read_function_string:Option<String>,
+    write_function_string:Option<String>,
+    function_params:Vec<String>,
+    write_transactions:bool,
+    ///expression giving the version of a row for optimistic locking - the version field or xmin
+    version_expr:Option<String>,
+    version_field:Option<String>,
+    conflict_copy:bool,
+    history_table:Option<String>,
+    deleted_field:Option<String>,
+    deleted_value:String,
+    live_value:String,
+    ///query listing the deleted rows, for tables with soft delete
+    trash_query_string:Option<String>,
+    temporal:Option<Temporal>,
+    ///the time this copy of a temporal table shows it as of
+    as_of:Option<String>,
+    uid:u32,
+    gid:u32,
+    ///permission bits before taking away those the role doesn't have privileges for
+    mode:u16,
+    mode_field:Option<String>,
+    owner_field:Option<String>,
+    group_field:Option<String>,
+    xattr_fields:Vec<String>,
+    xattr_column:Option<String>,
+    quota_bytes:Option<u64>,
+    quota_files:Option<u64>,
+    ///most bytes of writes to buffer for a file
+    write_cache:Option<u64>,
+    ///stage files being written and write them back whole
+    whole_file:bool,
+    entry_ttl:Duration,
+    attr_ttl:Duration,
+    keep_cache:bool,
+    direct_io:bool,
+}
+
+impl Table {
+    fn is_function_backed(&self) -> bool {
+        self.read_function_string.is_some() || self.write_function_string.is_some()
+    }
+
+    fn name_column(&self) -> &str {
+        self.name_field.as_deref().unwrap_or("name")
+    }
+
+    /// Query for the page of rows after the one with id `$1`, in id order, so that listing a big table
+    /// only fetches what fits in each readdir reply
+    fn page_query(&self) -> String {
+        format!("select * from ({}) as pgfs_rows where id > $1::bigint order by id limit {}",
+            self.query_string.trim_end().trim_end_matches(';'), LISTING_PAGE)
+    }
+
+    /// A read only copy of a temporal table which shows it as it was at a time
+    fn as_of(&self, timestamp: &str) -> Option<Table> {
+        let temporal = self.temporal.as_ref()?;
+        Some(Table {
+            query_string: temporal.as_of_query(&temporal.query_string, &self.table_name, timestamp),
+            data_query_string: temporal.as_of_query(&temporal.data_query_string, &self.table_name, timestamp),
+            trash_query_string: temporal.trash_query_string.as_ref().map(|query| temporal.as_of_query(query, &self.table_name, timestamp)),
+            read_only: true,
+            as_of: Some(timestamp.to_string()),
+            ..self.clone()
+        })
+    }
+
+    fn history_source(&self) -> Option<history::Source<'_>> {
+        Some(history::Source {
+            history_table: self.history_table.as_deref()?,
+            table_name: &self.table_name,
+            id_field: &self.id_field,
+            name_field: self.name_column(),
+            data_field: &self.bytea_field,
+        })
+    }
+
+    fn quota(&self) -> quota::TableQuota<'_> {
+        quota::TableQuota {
+            table_name: &self.table_name,
+            data_field: &self.bytea_field,
+            bytes: self.quota_bytes,
+            files: self.quota_files,
+            live_condition: self.deleted_field.as_ref()
+                .map(|deleted_field| format!("{} is not distinct from {}", deleted_field, self.live_value)),
+        }
+    }
+
+    fn xattr_source(&self) -> xattr::Source<'_> {
+        xattr::Source {
+            table_name: &self.table_name,
+            id_field: &self.id_field,
+            fields: &self.xattr_fields,
+            column: self.xattr_column.as_deref(),
+        }
+    }
+
+    /// Make a query on the table see the same rows as the table's directory, for temporal tables
+    fn scoped_query(&self, query: &str) -> String {
+        match (self.temporal.as_ref(), self.as_of.as_ref()) {
+            (Some(temporal), Some(timestamp)) => temporal.as_of_query(query, &self.table_name, timestamp),
+            (Some(temporal), None) => temporal.current_query(query, &self.table_name),
+            (None, _) => query.to_string(),
+        }
+    }
+
+    /// Size, created and modified times from a row returned by the query string
+    fn row_attr_values(&self, row: &postgres::Row) -> (u64, Option<SystemTime>, Option<SystemTime>) {
+        let mtime:Option<SystemTime> = match self.modified_field.as_ref() {
+            Some(modified_field) => {row.get::<&str, Option<SystemTime>>(modified_field.as_str())},
+            None => {None}
+        };
+        let ctime:Option<SystemTime> = match self.created_field.as_ref() {
+            Some(created_field) => {row.get::<&str, Option<SystemTime>>(created_field.as_str())}
+            None => {None}
+        };
+        let size = row.try_get::<&str, Option<i32>>("length").ok().flatten().unwrap_or(0) as u64;
+        (size, ctime, mtime)
+    }
+
+    /// Mode and owner from a row returned by the query string, for tables with columns for them
+    fn row_ownership(&self, row: &postgres::Row) -> Ownership {
+        let get = |field: &Option<String>| field.as_ref()
+            .and_then(|field| row.try_get::<&str, Option<i32>>(field.as_str()).ok().flatten());
+        Ownership {
+            mode: get(&self.mode_field).map(|mode| mode as u16 & 0o7777),
+            uid: get(&self.owner_field).map(|uid| uid as u32),
+            gid: get(&self.group_field).map(|gid| gid as u32),
+        }
+    }
 }
 
 type Inode = u64;
 
+/// Names of the tables mounted, for counting their rows, leaving out the `@<timestamp>` copies of them
+fn table_names(tables: &HashMap<String, Table>) -> Vec<&str> {
+    let mut names: Vec<&str> = tables.iter()
+        .filter(|(key, _)| !key.starts_with('@'))
+        .map(|(_, table)| table.table_name.as_str())
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+    names
+}
+
+/// The error for an extended attribute query which failed, or found no row
+fn xattr_error(e: Option<postgres::Error>) -> i32 {
+    match e {
+        Some(e) => {
+            let errno = roles::errno(&e, EIO);
+            dbg!(e);
+            errno
+        }
+        None => ENOENT,
+    }
+}
+
+/// SET clause writing `length` bytes of `placing` into a data field at `from`, counting from 1. Short values
+/// are padded with zeros up to the offset, so that writes past the end leave a hole rather than moving.
+fn overlay_clause(field: &str, placing: &str, from: &str, length: &str) -> String {
+    format!("{0} = overlay(coalesce({0}, ''::bytea) || decode(repeat('00', greatest({2} - 1 - coalesce(octet_length({0}), 0), 0)), 'hex') \
+        placing {1} from {2} for {3})", field, placing, from, length)
+}
+
 const TTL: std::time::Duration = std::time::Duration::from_secs(1); // 1 second
 
+/// A directory entry as (inode, offset of the next entry, kind, name)
+type DirEntry = (Inode, i64, FileType, String);
+
+/// Rows fetched at a time when listing a table
+const LISTING_PAGE: usize = 500;
+
+/// The readdir offset of the row with an id, in a table directory with `fixed_entries` entries before the
+/// rows. Offsets follow ids, so a listing carries on after the last row it returned whatever has changed.
+fn row_offset(fixed_entries: i64, id: i32) -> i64 {
+    fixed_entries + 1 + (id as i64 - i32::MIN as i64)
+}
+
 const ROOT: FileAttr = FileAttr {
     ino: 1,
     size: 0,
@@ -282,8 +1843,8 @@ const ROOT: FileAttr = FileAttr {
     kind: FileType::Directory,
     perm: 0o755,
     nlink: 3,
-    uid: 1001,
-    gid: 20,
+    uid: 0,
+    gid: 0,
     rdev: 0,
     flags: 0,
     blksize: 512,
@@ -296,6 +1857,18 @@ impl Filesystem for ByteaFileSystem {
         if let Err(_e) = config.set_max_write(1024*128) {
             dbg!("Unable to set max write");
         }
+        //have the kernel pass locks on to us rather than only enforcing them locally
+        if self.locks.is_some() && let Err(e) = config.add_capabilities(consts::FUSE_POSIX_LOCKS | consts::FUSE_FLOCK_LOCKS) {
+            log::warn!("Kernel does not support remote locks ({:x}), so locks will only apply to this host", e);
+        }
+        //drop cached pages of files whose size or mtime has changed, so keep_cache tables don't show stale data
+        if let Err(e) = config.add_capabilities(consts::FUSE_AUTO_INVAL_DATA) {
+            log::warn!("Kernel does not support invalidating cached pages ({:x})", e);
+        }
+        //let the kernel list directories with attributes, when it sees lookups following a listing
+        if let Err(e) = config.add_capabilities(consts::FUSE_DO_READDIRPLUS | consts::FUSE_READDIRPLUS_AUTO) {
+            log::warn!("Kernel does not support readdirplus ({:x})", e);
+        }
         Ok(())
     }
 
@@ -305,17 +1878,38 @@ impl Filesystem for ByteaFileSystem {
         self.file_inodes.clear();
         self.entries.clear();
         self.tables.clear();
-        self.cache.clear();
+        self.write_buffers.clear();
+        self.open_files.clear();
         //self.db_client.close(); - should do this but need to move
         //could Option it, then acces via fn with expect? should be a better way
     }
 
     fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.apply_changes();
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
         if parent == 1 { //child of root dir
             dbg!("lookup root");
-            if let Some(inode) = self.table_dir_inodes.get_by_right(name.to_str().unwrap_or("")) {
+            if let Some(inode) = self.table_dir_inodes.get_by_right(name.to_str().unwrap_or("")).copied() {
                 dbg!("folder", name, inode);
-                reply.entry(&TTL, &ByteaFileSystem::dir_file_attr(*inode), 0)
+                let attr = self.presented_attr(&ByteaFileSystem::dir_file_attr(inode));
+                reply.entry(&self.entry_ttl(inode), &attr, 0)
+            } else if let Some(channel_dir) = self.channel_dir.as_ref()
+                && name.to_str() == Some(channel_dir.name.as_str()) {
+                let attr = self.presented_attr(&ByteaFileSystem::dir_file_attr(channel_dir.inode));
+                reply.entry(&TTL, &attr, 0)
+            } else if let Some(timestamp) = name.to_str().and_then(|name| name.strip_prefix('@')) {
+                //the tables as they were at a time
+                match self.as_of_dir(timestamp) {
+                    Ok(inode) => {
+                        let attr = *self.inode_file_attrs.get(&inode).unwrap();
+                        let attr = self.presented_attr(&attr);
+                        reply.entry(&TTL, &attr, 0)
+                    }
+                    Err(e) => reply.error(e),
+                }
             } else {
                 dbg!("request for non-existant file", name);
 
@@ -323,14 +1917,23 @@ impl Filesystem for ByteaFileSystem {
             }
         } else {
             dbg!("lookup file {} {}", parent, name);
-            if let Some(inode) = self.entries.get_by_left(&ChildNode {
+            let child = ChildNode {
                 parent,
                 name: name.to_str().unwrap_or("").to_string(),
-            }) {
-                if let Some(attr) = self.inode_file_attrs.get(inode) {
+            };
+            if !self.entries.contains_left(&child) && self.history_nodes.contains_key(&parent) {
+                let _ = self.list_history_dir(parent);
+            }
+            if !self.entries.contains_left(&child) && self.trash_dirs.contains_right(&parent) {
+                let _ = self.list_trash(parent);
+            }
+            if let Some(inode) = self.entries.get_by_left(&child).copied() {
+                if let Err(e) = self.check_visible(inode) {
+                    reply.error(e);
+                } else if let Some(attr) = self.inode_file_attrs.get(&inode).copied() {
                     dbg!("found entry");
                   //  dbg!(attr);
-                    reply.entry(&TTL, attr, 0);
+                    reply.entry(&self.entry_ttl(inode), &self.presented_attr(&attr), 0);
                 } else {
                     dbg!("no file attr entry");
                 }
@@ -347,17 +1950,24 @@ impl Filesystem for ByteaFileSystem {
     }
 
     fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh:Option<u64>, reply: ReplyAttr) {
+        self.apply_changes();
         //   dbg!(_req);
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
         match ino {
             1 => {
                 dbg!("getattr root ");
-                reply.attr(&TTL, &ROOT);
+                reply.attr(&TTL, &self.presented_attr(&ROOT));
             }
             _ => {
                 dbg!("getattr file");
-                if self.inode_file_attrs.contains_key(&ino as &Inode) {
+                if let Err(e) = self.check_visible(ino) {
+                    reply.error(e);
+                } else if let Some(attr) = self.inode_file_attrs.get(&ino as &Inode).copied() {
                     dbg!("got attr");
-                    reply.attr(&TTL, self.inode_file_attrs.get(&ino as &Inode).unwrap());
+                    reply.attr(&self.attr_ttl(ino), &self.presented_attr(&attr));
                 } else {
                     dbg!("not got attr");
                     reply.error(ENOENT);
@@ -366,6 +1976,7 @@ impl Filesystem for ByteaFileSystem {
         }
     }
 
+    #[allow(clippy::collapsible_if, clippy::unnecessary_unwrap)]
     fn setattr(&mut self, _req: &Request<'_>,
                ino: u64, _mode: Option<u32>,
                _uid: Option<u32>,
@@ -379,51 +1990,129 @@ impl Filesystem for ByteaFileSystem {
                _chgtime: Option<SystemTime>,
                _bkuptime: Option<SystemTime>,
                _flags: Option<u32>, reply: ReplyAttr) {
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
+        if self.read_only_file(ino) {
+            reply.error(EROFS);
+            return;
+        }
+        if let Err(e) = self.check_owner_change(_req.uid(), ino, _mode, _uid, _gid) {
+            reply.error(e);
+            return;
+        }
         let mut error:Option<i32> = None;
-        if let Some(size) = size {
+        let mut truncated = false;
+        let mut changed = false;
+        if let Some(size) = size
+            && let Some((table, _)) = self.file_inodes.get(&ino)
+            && table.is_function_backed() {
+            if let Err(e) = self.truncate_function_content(ino, _fh, size) {
+                error = Some(e);
+            }
+            truncated = true;
+        } else if let Some(size) = size
+            && self.staged.contains_key(&ino) {
+            let result = self.make_room(ino, size).and_then(|_| self.load_staged(ino, Some(size))).and_then(|_| {
+                self.staged.get_mut(&ino).unwrap().set_len(&self.stage_config, size).map_err(|e| {
+                    log::warn!("Unable to truncate staged file: {}", e);
+                    EIO
+                })
+            });
+            match result {
+                Ok(()) => self.release_room(ino, size),
+                Err(e) => error = Some(e),
+            }
+            if let Some(attr) = self.inode_file_attrs.get_mut(&ino) {
+                attr.size = size;
+            }
+            truncated = true;
+        } else if let Some(size) = size {
             dbg!("truncate to size", size);
 
                 //truncate the file
-                if let Some((table,pgid)) = self.file_inodes.get(&ino) {
-                    let query_string = format!("update {} set {} = substring({}, 1, $1) where {}  = $2;", table.table_name, table.bytea_field, table.bytea_field, table.id_field);
-                     if self.db_client.execute(query_string.as_str(), &[&(size as i32), &(pgid.pg_id as i32)]).is_err() {
-                         error = Some(EIO);
-                         dbg!("Failed to truncate: ", query_string);
+                if let Some((table,_)) = self.file_inodes.get(&ino).cloned() {
+                    let set_clause = format!("{} = substring({}, 1, $1)", table.bytea_field, table.bytea_field);
+                     match self.write_data_to_postgres(ino, None).and_then(|_| self.make_room(ino, size)).and_then(|_| self.save_history(ino)).and_then(|_| self.update_row(ino, &set_clause, &[&(size as i32)])) {
+                         Ok(()) => self.release_room(ino, size),
+                         Err(e) => {
+                             error = Some(e);
+                             dbg!("Failed to truncate: ", set_clause);
+                         }
                      }
                      if let Some(attr) = self.inode_file_attrs.get_mut(&ino) {
                          attr.size = size;
                      }
+                     truncated = true;
                 }
         }
-        if let Some((table,pgid)) = self.file_inodes.get(&ino) {
+        if let Some((table,_)) = self.file_inodes.get(&ino).cloned() {
             if _ctime.is_some() && table.created_field.is_some() {
-                let query_string = format!("update {} set {} = $1 where {} = $2", table.table_name, table.created_field.as_ref().unwrap(), table.id_field );
-                if self.db_client.execute(query_string.as_str(), &[_ctime.as_ref().unwrap(), &(pgid.pg_id as i32)]).is_err() {
-                    error = Some(EPERM); //calling it a permissions error to avoid issues with clients
+                if let Err(e) = self.update_row(ino, &format!("{} = $1", table.created_field.as_ref().unwrap()), &[_ctime.as_ref().unwrap()]) {
+                    //calling it a permissions error to avoid issues with clients, unless it is a conflict
+                    error = Some(if e == ESTALE { e } else { EPERM });
                 }
                 if let Some(attr) = self.inode_file_attrs.get_mut(&ino) {
                     attr.ctime = _ctime.unwrap();
                 }
+                changed = true;
             }
         }
-        if let Some((table,pgid)) = self.file_inodes.get(&ino) {
+        if let Some((table,_)) = self.file_inodes.get(&ino).cloned() {
             if _mtime.is_some() && table.modified_field.is_some() {
                 let time:SystemTime = match _mtime.unwrap() {
                     TimeOrNow::SpecificTime(t) => { t }
                     TimeOrNow::Now => { SystemTime::now() }
                 };
-                let query_string = format!("update {} set {} = $1 where {} = $2", table.table_name, table.modified_field.as_ref().unwrap(), table.id_field );
-                if self.db_client.execute(query_string.as_str(), &[&time, &(pgid.pg_id as i32)]).is_err() {
-                    error = Some(EPERM); //calling it a permissions error to avoid issues with clients
+                if let Err(e) = self.update_row(ino, &format!("{} = $1", table.modified_field.as_ref().unwrap()), &[&time]) {
+                    error = Some(if e == ESTALE { e } else { EPERM });
                 }
                 if let Some(attr) = self.inode_file_attrs.get_mut(&ino) {
                     attr.mtime = time;
                 }
+                changed = true;
+            }
+        }
+        if let Some((table,_)) = self.file_inodes.get(&ino).cloned() {
+            //chmod and chown, for tables which keep them. Otherwise they are ignored, as they always were
+            let mode = _mode.filter(|_| table.mode_field.is_some()).map(|mode| mode as u16 & 0o7777);
+            let uid = _uid.filter(|_| table.owner_field.is_some());
+            let gid = _gid.filter(|_| table.group_field.is_some());
+            let mut assignments = vec![];
+            let mut values: Vec<i32> = vec![];
+            for (field, value) in [(&table.mode_field, mode.map(|mode| mode as i32)), (&table.owner_field, uid.map(|uid| uid as i32)), (&table.group_field, gid.map(|gid| gid as i32))] {
+                if let (Some(field), Some(value)) = (field, value) {
+                    values.push(value);
+                    assignments.push(format!("{} = ${}", field, values.len()));
+                }
+            }
+            if !assignments.is_empty() {
+                let params: Vec<&(dyn ToSql + Sync)> = values.iter().map(|value| value as &(dyn ToSql + Sync)).collect();
+                match self.update_row(ino, &assignments.join(", "), &params) {
+                    Ok(()) => {
+                        let ownership = self.ownership.entry(ino).or_default();
+                        ownership.mode = mode.or(ownership.mode);
+                        ownership.uid = uid.or(ownership.uid);
+                        ownership.gid = gid.or(ownership.gid);
+                        changed = true;
+                    }
+                    Err(e) => error = Some(e),
+                }
             }
         }
 
-        if error.is_none() && let Some(attr) = self.inode_file_attrs.get(&ino) {
-            reply.attr(&TTL, attr);
+        if error.is_none() {
+            let caller = Caller::of(_req);
+            if truncated {
+                self.audit(&caller, "truncate", ino, size.map(|size| size as i64));
+            }
+            if changed {
+                self.audit(&caller, "setattr", ino, None);
+            }
+        }
+        if error.is_none() && let Some(attr) = self.inode_file_attrs.get(&ino).copied() {
+            reply.attr(&self.attr_ttl(ino), &self.presented_attr(&attr));
         } else {
             reply.error(error.unwrap_or(ENODATA));
         }
@@ -442,31 +2131,34 @@ impl Filesystem for ByteaFileSystem {
 
     fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         //unlink called by vim when trying to delete a swap file it thinks it found
-        dbg!("unlink", name);
-
-        if let Some(ino )= self.entries.remove_by_left(&ChildNode{parent, name: name.to_str().unwrap_or("").to_string()})
-        {
-            let table = self.tables.get(self.table_dir_inodes.get_by_left(&parent).unwrap_or(&"".to_string()));
-            let table = table.unwrap();
-            let default_delete_query_string =
-                format!("delete from {} where {} = $1", table.table_name, table.id_field);
-            let delete_query_string =
-            table.delete_query_string.as_ref().unwrap_or(
-                &default_delete_query_string);
-            let ino = ino.1;
-            //self.write_data_to_postgres(ino, None);
-            if let Some((_,pgid)) = self.file_inodes.get(&ino) {
-                if let Err(_e) = self.db_client.execute(delete_query_string.as_str(), &[&(pgid.pg_id as i32)]) {
-                    dbg!("Failed to delete: ", delete_query_string);
-                }
-                //if there is something in the cache, then we are deleting a file
-                //which has not been fully written. Add a config (default true)
-                //to flush first
-                self.cache.remove(&ino);
-                self.file_inodes.remove(&ino);
+        dbg!("unlink", name);
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
+        if self.read_only_dir(parent) {
+            reply.error(EROFS);
+            return;
+        }
+        if let Some(channel_dir) = self.channel_dir.as_mut()
+            && channel_dir.inode == parent {
+            if let Some((_, ino)) = self.entries.remove_by_left(&ChildNode{parent, name: name.to_str().unwrap_or("").to_string()}) {
+                channel_dir.channel_inodes.remove(&ino);
                 self.inode_file_attrs.remove(&ino);
-            } else {
-                dbg!("missing file inode");
+            }
+            reply.ok();
+            return;
+        }
+
+        if let Some(ino) = self.entries.get_by_left(&ChildNode{parent, name: name.to_str().unwrap_or("").to_string()}).copied()
+            && self.file_inodes.contains_key(&ino) {
+            let target = self.audit_target(ino);
+            match self.delete_file(ino, false) {
+                Ok(()) => self.record_audit(&Caller::of(_req), "unlink", ino, target, None),
+                //the file is gone from the listing either way, as before
+                Err(e) => {
+                    dbg!(e);
+                }
             }
         }
         reply.ok();
@@ -480,9 +2172,65 @@ impl Filesystem for ByteaFileSystem {
         reply.error(ENOSYS);
     }
 
+    #[allow(clippy::collapsible_if)]
     fn rename(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, _flags: u32, reply: ReplyEmpty) {
         //run sql to rename the file
         //change the references in this struct
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
+        if self.read_only_dir(parent) || self.read_only_dir(newparent) {
+            reply.error(EROFS);
+            return;
+        }
+        let ino = self.entries.get_by_left(&ChildNode { parent, name: name.to_str().unwrap_or("").to_string() }).copied();
+        if let Some(ino) = ino
+            && self.trash_dirs.get_by_left(&parent) == Some(&newparent) {
+            //moving a file into the trash deletes it
+            let Some((table, pgid)) = self.file_inodes.get(&ino).cloned() else {
+                reply.error(ENOENT);
+                return;
+            };
+            let (client, statements) = self.connection_for(ino);
+            if let Err(e) = trash::soft_delete(client, statements, &table, pgid.pg_id as i32) {
+                dbg!(e);
+                reply.error(EIO);
+                return;
+            }
+            let size = self.inode_file_attrs.get(&ino).map_or(0, |attr| attr.size);
+            self.quotas.file_removed(&table.table_name, size);
+            self.audit(&Caller::of(_req), "unlink", ino, None);
+            self.move_file_entry(ino, newparent, newname.to_str().unwrap_or("").to_string());
+            reply.ok();
+            return;
+        }
+        if let Some(ino) = ino
+            && self.trash_dirs.get_by_right(&parent) == Some(&newparent)
+            && let Some((table, pgid)) = self.file_inodes.get(&ino).cloned() {
+            //moving a file out of the trash restores it, replacing any file with its new name
+            let newname = newname.to_str().unwrap_or("").to_string();
+            if let Some(existing) = self.entries.get_by_left(&ChildNode { parent: newparent, name: newname.clone() }).copied()
+                && let Err(e) = self.delete_file(existing, false) {
+                reply.error(e);
+                return;
+            }
+            if let Err(e) = trash::restore(&mut self.db_client, &mut self.statements, &table, pgid.pg_id as i32, &newname) {
+                dbg!(e);
+                reply.error(EIO);
+                return;
+            }
+            let size = self.inode_file_attrs.get(&ino).map_or(0, |attr| attr.size);
+            self.quotas.file_added(&table.table_name, size);
+            self.move_file_entry(ino, newparent, newname);
+            self.audit(&Caller::of(_req), "restore", ino, None);
+            reply.ok();
+            return;
+        }
+        if self.trash_dirs.contains_right(&parent) || self.trash_dirs.contains_right(&newparent) {
+            reply.error(EROFS);
+            return;
+        }
         if parent != newparent {
             reply.error(ENOSYS);
             return;
@@ -491,10 +2239,21 @@ impl Filesystem for ByteaFileSystem {
         if let Some(table_name) = self.table_dir_inodes.get_by_left(&parent) {
             if let Some(table) = self.tables.get(table_name) {
                 let query = format!("update {} set {} = ($1) where {} = $2", table_name, table.name_field.as_ref().unwrap(), &table.name_field.as_ref().unwrap());
-                if let Err(_e) = self.db_client.execute(query.as_str(), &[&newname.to_str(), &name.to_str()]) {
+                let ino = self.entries.get_by_left(&ChildNode { parent, name: name.to_str().unwrap_or("").to_string() }).copied().unwrap_or(0);
+                let (client, statements) = self.connection_for(ino);
+                if let Err(_e) = statements.execute(client, query.as_str(), &[&newname.to_str(), &name.to_str()]) {
                     reply.error(EIO); //could do better with the error here maybe
                     return;
                 }
+                //renaming through the filesystem isn't a conflicting change to a file being written
+                if self.versions.contains_key(&ino) {
+                    let _ = self.record_version(ino);
+                }
+                //the audit record takes the file's name from its entry, so it has to be under the new name first
+                if self.file_inodes.contains_key(&ino) {
+                    self.move_file_entry(ino, parent, newname.to_str().unwrap_or("").to_string());
+                }
+                self.audit(&Caller::of(_req), "rename", ino, None);
             }
         }
         reply.ok();
@@ -509,15 +2268,153 @@ impl Filesystem for ByteaFileSystem {
 
     fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: ReplyOpen) {
         dbg!("open", _ino, _flags);
-        reply.opened(_ino, _flags as u32);
+        self.apply_changes();
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
+        if self.change_feed.is_none() {
+            //nothing else tells us if another application has changed the file
+            self.block_cache.invalidate(_ino);
+        }
+        let mut open_flags = self.cache_flags(_ino);
+        let mut content = None;
+        if let Some(channel) = self.channel_name(_ino).cloned() {
+            let fh = self.open_handle(_ino, _flags & O_ACCMODE != O_RDONLY, None);
+            if _flags & O_ACCMODE != O_WRONLY {
+                self.channel_dir.as_ref().unwrap().hub.subscribe(fh, &channel, _flags & O_NONBLOCK != 0);
+            }
+            reply.opened(fh, consts::FOPEN_DIRECT_IO | consts::FOPEN_NONSEEKABLE);
+            return;
+        }
+        if let Some((table, _)) = self.file_inodes.get(&_ino)
+            && table.is_function_backed() {
+            let writing = _flags & O_ACCMODE != O_RDONLY;
+            if writing && table.write_function_string.is_none() {
+                reply.error(EROFS);
+                return;
+            }
+            if writing && _flags & O_TRUNC != 0 {
+                //only seen if the kernel does atomic O_TRUNC, otherwise a truncate follows the open
+                content = Some(Vec::new());
+            }
+            //the size isn't known until the function has been called
+            open_flags |= consts::FOPEN_DIRECT_IO;
+        }
+        let writing = _flags & O_ACCMODE != O_RDONLY;
+        if writing && self.read_only_file(_ino) {
+            reply.error(EROFS);
+            return;
+        }
+        if writing
+            && self.file_inodes.get(&_ino).is_some_and(|(table, _)| table.history_table.is_some())
+            && !self.open_files.values().any(|open_file| open_file.ino == _ino && open_file.writable) {
+            //this write session starts from the current content, which is saved as a version on the first change
+            self.unsaved_history.insert(_ino, _req.uid());
+        }
+        let fh = self.open_handle(_ino, writing, content);
+        if writing && let Err(e) = self.begin_transaction_if_configured(_ino, fh).and_then(|_| self.record_version_if_new(_ino)) {
+            self.open_files.remove(&fh);
+            let _ = self.end_transaction(_ino, fh);
+            reply.error(e);
+            return;
+        }
+        if writing {
+            self.stage_if_configured(_ino);
+        }
+        reply.opened(fh, open_flags);
     }
 
     fn read(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, size: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyData) {
         dbg!("read");
-        self.write_data_to_postgres(ino, None);
+        self.apply_changes();
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
+        if self.channel_name(ino).is_some() {
+            self.channel_dir.as_ref().unwrap().hub.read(_fh, size, reply);
+            return;
+        }
+        if let Some((table, _)) = self.file_inodes.get(&ino)
+            && table.is_function_backed() {
+            match self.function_content(_fh) {
+                Ok(content) => {
+                    let start = cmp::min(offset as usize, content.len());
+                    let end = cmp::min(start + size as usize, content.len());
+                    reply.data(&content[start..end]);
+                }
+                Err(e) => reply.error(e),
+            }
+            return;
+        }
+        if let Some(HistoryNode::Version { table_inode, version_id }) = self.history_nodes.get(&ino).copied() {
+            let history_table = self.table_dir_inodes.get_by_left(&table_inode)
+                .and_then(|name| self.tables.get(name))
+                .and_then(|table| table.history_table.clone())
+                .unwrap_or_default();
+            match history::read(&mut self.db_client, &mut self.statements, &history_table, version_id, offset, size) {
+                Ok(data) => reply.data(&data),
+                Err(e) => {
+                    dbg!(e);
+                    reply.error(EIO);
+                }
+            }
+            return;
+        }
+        if let Some(staged) = self.staged.get_mut(&ino)
+            && staged.loaded() {
+            match staged.read(offset as u64, size) {
+                Ok(data) => reply.data(&data),
+                Err(e) => {
+                    dbg!(e);
+                    reply.error(EIO);
+                }
+            }
+            return;
+        }
+        //reads see earlier writes, so one which can't be written out can't be read either
+        if let Err(e) = self.write_data_to_postgres(ino, None) {
+            self.write_errors.insert(ino, e);
+            reply.error(EIO);
+            return;
+        }
         if let Some((table, pgid)) = self.file_inodes.get(&ino) {
-            let p_row = self.db_client.query_one(table.data_query_string.as_str(), &[&(pgid.pg_id as i32), &(1 + offset as i32), &(size as i32)]);
-            if let Ok(res) = p_row {
+            let in_transaction = self.transactions.get(&ino).is_some_and(|transaction| transaction.handles.contains(&_fh));
+            if self.block_cache.enabled() && self.roles.is_none() && !in_transaction {
+                let file_size = self.inode_file_attrs.get(&ino).map_or(0, |attr| attr.size);
+                if let Some(pipeline) = self.pipeline.as_ref() {
+                    pipeline.read_cached(&self.block_cache, ino, file_size, offset as u64, size, table.data_query_string.clone(),
+                                         pgid.pg_id as i32, reply);
+                    return;
+                }
+                let (query, id) = (table.data_query_string.as_str(), pgid.pg_id as i32);
+                let (db_client, statements) = (&mut self.db_client, &mut self.statements);
+                let data = self.block_cache.read(ino, file_size, offset as u64, size, |start, length| {
+                    statements.query_opt(db_client, query, &[&id, &(1 + start as i32), &(length as i32)])
+                        .map(|row| row.and_then(|row| row.get::<usize, Option<Vec<u8>>>(0)).unwrap_or_default())
+                });
+                match data {
+                    Ok(data) => reply.data(&data),
+                    Err(e) => {
+                        log::warn!("Unable to read {}: {}", ino, e);
+                        reply.error(roles::errno(&e, EIO))
+                    }
+                }
+                return;
+            }
+            if let Some(pipeline) = self.pipeline.as_ref()
+                && !in_transaction {
+                pipeline.read(table.data_query_string.clone(), pgid.pg_id as i32, offset as u64, size, reply);
+                return;
+            }
+            //a handle writing in a transaction should see its own changes, everyone else sees the last commit
+            let (client, statements) = match self.transactions.get_mut(&ino) {
+                Some(transaction) if transaction.handles.contains(&_fh) => (&mut transaction.client, &mut transaction.statements),
+                _ => (&mut self.db_client, &mut self.statements),
+            };
+            let p_row = statements.query_opt(client, table.data_query_string.as_str(), &[&(pgid.pg_id as i32), &(1 + offset as i32), &(size as i32)]);
+            if let Ok(Some(res)) = p_row {
                 let bytes: Option<&[u8]> = res.get(0);
                 let empty = Vec::new();
                 let data = bytes.unwrap_or(&empty);
@@ -535,19 +2432,99 @@ impl Filesystem for ByteaFileSystem {
     fn write(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, data: &[u8], _write_flags: u32, _flags: i32, _lock_owner: Option<u64>, reply: ReplyWrite) {
         //lookup ino - will have been created
         dbg!("write" ,data.len());
-        if let Some((cached_offset, cached_data)) = self.cache.get_mut(&ino) {
-            if *cached_offset + cached_data.len() as i64 == offset && cached_data.len() + data.len() <= 2097152 {
-                cached_data.extend_from_slice(data);
-                if let Some(attrs) = self.inode_file_attrs.get_mut(&ino) {
-                        attrs.size = max(attrs.size, (offset as i64 + (data.len() as i64)) as u64);
-                        attrs.blocks = (attrs.size + 1) / (attrs.blksize as u64)
-                }
-                dbg!("Extended data cache");
-            } else {
-                self.write_data_to_postgres(ino, Some((offset, data)));
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
+        if let Some(channel) = self.channel_name(ino).cloned() {
+            match channels::notify(&mut self.db_client, &mut self.statements, &channel, data) {
+                Ok(()) => reply.written(data.len() as u32),
+                Err(e) => {
+                    dbg!(e);
+                    reply.error(EIO);
+                }
+            }
+            return;
+        }
+        if let Some((table, _)) = self.file_inodes.get(&ino)
+            && table.is_function_backed() {
+            match self.function_content(_fh) {
+                Ok(content) => {
+                    //the kernel's idea of the end of the file is only a guess until the function is called
+                    let offset = if _flags & O_APPEND != 0 { content.len() } else { offset as usize };
+                    let end = offset + data.len();
+                    if content.len() < end {
+                        content.resize(end, 0);
+                    }
+                    content[offset..end].copy_from_slice(data);
+                    let len = content.len() as u64;
+                    self.open_files.get_mut(&_fh).unwrap().dirty = true;
+                    if let Some(attrs) = self.inode_file_attrs.get_mut(&ino) {
+                        attrs.size = len;
+                    }
+                    self.note_write(Caller::of(_req), ino, data.len());
+                    reply.written(data.len() as u32);
+                }
+                Err(e) => reply.error(e),
+            }
+            return;
+        }
+        if self.transactions.get(&ino).is_some_and(|transaction| transaction.failed) {
+            //the transaction will be rolled back, so don't pretend to write anything else
+            reply.error(EIO);
+            return;
+        }
+        if let Err(e) = self.make_room(ino, (offset + data.len() as i64) as u64) {
+            reply.error(e);
+            return;
+        }
+        if self.staged.contains_key(&ino) {
+            let result = self.load_staged(ino, None).and_then(|_| {
+                self.staged.get_mut(&ino).unwrap().write(&self.stage_config, offset as u64, data).map_err(|e| {
+                    log::warn!("Unable to stage write: {}", e);
+                    EIO
+                })
+            });
+            if let Err(e) = result {
+                reply.error(e);
+                return;
+            }
+            if let Some(attrs) = self.inode_file_attrs.get_mut(&ino) {
+                attrs.size = max(attrs.size, (offset + (data.len() as i64)) as u64);
+                attrs.blocks = (attrs.size + 1) / (attrs.blksize as u64)
+            }
+            self.note_write(Caller::of(_req), ino, data.len());
+            reply.written(data.len() as u32);
+            return;
+        }
+        let limit = self.write_buffers.file_limit(self.file_inodes.get(&ino).and_then(|(table, _)| table.write_cache));
+        if !self.write_buffers.fits(ino, data.len(), limit)
+            && let Err(e) = self.write_data_to_postgres(ino, None) {
+            reply.error(e);
+            return;
+        }
+        if data.len() as u64 > limit {
+            if let Err(e) = self.write_data_to_postgres(ino, Some((offset, data))) {
+                reply.error(e);
+                return;
             }
         } else {
-            self.cache.insert(ino, (offset, Vec::from(data)));
+            self.write_buffers.write(ino, offset as u64, data);
+            if let Some(attrs) = self.inode_file_attrs.get_mut(&ino) {
+                attrs.size = max(attrs.size, (offset + (data.len() as i64)) as u64);
+                attrs.blocks = (attrs.size + 1) / (attrs.blksize as u64)
+            }
+        }
+        self.note_write(Caller::of(_req), ino, data.len());
+        //write out buffers which have waited too long or don't fit, keeping errors for their next flush
+        for due in self.write_buffers.due() {
+            if let Err(e) = self.write_data_to_postgres(due, None) {
+                if due == ino {
+                    reply.error(e);
+                    return;
+                }
+                self.write_errors.insert(due, e);
+            }
         }
         reply.written(data.len() as u32);
 
@@ -555,26 +2532,107 @@ impl Filesystem for ByteaFileSystem {
 
     fn flush(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
         dbg!("flush");
-        self.write_data_to_postgres(ino, None);
-        reply.ok();
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
+        //closing any descriptor for a file drops the process's POSIX locks on it
+        if let Some(locks) = self.locks.as_ref() {
+            locks.release_owner(ino, _lock_owner);
+        }
+        if let Err(e) = self.write_out(ino) {
+            reply.error(e);
+            return;
+        }
+        //write a staged file back when the last handle writing it is closed, so that close() sees any error
+        if !self.open_files.iter().any(|(fh, open_file)| *fh != _fh && open_file.ino == ino && open_file.writable)
+            && let Err(e) = self.write_staged(ino) {
+            reply.error(e);
+            return;
+        }
+        if self.transactions.get(&ino).is_some_and(|transaction| transaction.failed) {
+            reply.error(EIO);
+            return;
+        }
+        match self.write_function_content(_fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
     }
 
 
 
     fn release(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, _flags: i32, _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
-        self.write_data_to_postgres(ino, None);
-        reply.ok();
+        //the kernel releases files without the credentials of the process which opened them
+        let role = self.open_files.get(&_fh).and_then(|open_file| open_file.role.clone());
+        let last_writer = !self.open_files.iter().any(|(fh, open_file)| *fh != _fh && open_file.ino == ino && open_file.writable);
+        let mut result = self.use_role(role)
+            .and_then(|_| self.write_out(ino).and(self.write_function_content(_fh)));
+        if last_writer {
+            result = result.and_then(|_| self.write_staged(ino));
+            self.staged.remove(&ino);
+        }
+        //recorded before the transaction is committed, so that it is part of it
+        self.audit_writes(ino);
+        let result = result.and(self.end_transaction(ino, _fh));
+        self.open_files.remove(&_fh);
+        //flock locks belong to the open file, so go when it is released
+        if let Some(lock_owner) = _lock_owner
+            && let Some(locks) = self.locks.as_ref() {
+            locks.release_owner(ino, lock_owner);
+        }
+        if !self.open_files.values().any(|open_file| open_file.ino == ino && open_file.writable) {
+            self.versions.remove(&ino);
+            self.unsaved_history.remove(&ino);
+        }
+        if self.channel_name(ino).is_some() {
+            self.channel_dir.as_ref().unwrap().hub.unsubscribe(_fh);
+        }
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
     }
 
 
     fn fsync(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
         dbg!("fsync");
-        self.write_data_to_postgres(ino, None);
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
+        if let Err(e) = self.write_out(ino).and_then(|_| self.write_staged(ino)) {
+            reply.error(e);
+            return;
+        }
+        self.audit_writes(ino);
+        if let Some(transaction) = self.transactions.get_mut(&ino) {
+            if transaction.failed {
+                reply.error(EIO);
+                return;
+            }
+            self.block_cache.invalidate(ino);
+            match transaction.checkpoint() {
+                Ok(true) => {}
+                Ok(false) => {
+                    dbg!("fsync found a failed write", ino);
+                    reply.error(EIO);
+                    return;
+                }
+                Err(e) => {
+                    dbg!(e);
+                    transaction.failed = true;
+                    reply.error(EIO);
+                    return;
+                }
+            }
+        }
         reply.ok();
     }
 
     fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
         dbg!("opendir");
+        self.apply_changes();
         //   dbg!(ino);
         reply.opened(ino, 0)
     }
@@ -582,88 +2640,68 @@ impl Filesystem for ByteaFileSystem {
     fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
         //  dbg!(format!("readdir {} {} {}", ino, fh, offset));
         dbg!("readdir");
-        if ino == 1 {
-            let default_entries = vec![
-                (1, FileType::Directory, "."),
-                (1, FileType::Directory, ".."),
-            ];
-            let mut i = 1;
-            let table_entries: Vec<(u64, FileType, &str)> = self.tables.values().map(|tab| {
-                i = i + 1;
-                (i as u64, FileType::Directory, tab.table_name.as_str())
-            }).collect();
-
-            for (i, entry) in default_entries.into_iter().chain(table_entries.into_iter()).enumerate().skip(offset as usize) {
-                // i + 1 means the index of the next entry
-                if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
-                    break;
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
+        let mut offset = offset;
+        loop {
+            let (entries, complete) = match self.list_dir(ino, offset) {
+                Ok(listing) => listing,
+                Err(e) => {
+                    reply.error(e);
+                    return;
                 }
-            }
-            reply.ok();
-        } else {
-            if ino > 1 {
-                if let Some(table_name) = self.table_dir_inodes.get_by_left(&ino) {
-                    if offset == 0 {
-                        let _=reply.add(ino, 1, FileType::Directory, ".");
-                    }
-                    if offset <= 1 {
-                        let _=reply.add(ino, 2, FileType::Directory, "..");
-                    }
-                    if let Some(table) = self.tables.get(table_name) {
-                        let mut i = 3;
-                        for row in self.db_client.query(table.query_string.as_str(), &[]).unwrap().into_iter().skip(cmp::max(offset - 2, 0) as usize) {
-                            dbg!("add {}", row.get::<&str, String>("name"));
-                            let child = ChildNode { parent: ino, name: row.get("name") };
-                            if self.entries.contains_left(&child) {
-                                let _=reply.add(*self.entries.get_by_left(&child).unwrap(), i, FileType::RegularFile, row.get::<&str, String>("name"));
-                                i+=1;
-                                continue
-                            }
-                            let t = Table {
-                                table_name: table.table_name.clone(),
-                                id_field: table.id_field.clone(),
-                                length_field: table.length_field.clone(),
-                                bytea_field: table.bytea_field.clone(),
-                                name_field: table.name_field.clone(),
-                                query_string: table.query_string.clone(),
-                                data_query_string: table.data_query_string.clone(),
-                                delete_query_string: table.delete_query_string.clone(),
-                                read_only:table.read_only,
-                                created_field:table.created_field.clone(),
-                                modified_field:table.modified_field.clone(),
-                            };
-                            self.next_inode += 1;
-                            let inode = self.next_inode;
-                            let _=reply.add(inode, i, FileType::RegularFile, row.get::<&str, String>("name"));
-                            let mtime:Option<SystemTime> = match table.modified_field.as_ref() {
-                                Some(modified_field) => {row.get::<&str, Option<SystemTime>>(modified_field.as_str())},
-                                None => {None}
-                            };
-                            let ctime:Option<SystemTime> = match table.created_field.as_ref() {
-                                Some(created_field) => {row.get::<&str, Option<SystemTime>>(created_field.as_str())}
-                                None => {None}
-                            };
-                            self.inode_file_attrs.insert(inode, ByteaFileSystem::file_attr(inode, row.get::<&str, Option<i32>>("length").unwrap_or(0) as u64, ctime, mtime));
-                            i += 1;
-                            let pgid = PgId {
-                                table_inode: ino,
-                                pg_id: row.get::<&str, i32>("id") as u64,
-                            };
-                            self.file_inodes.insert(inode, (t, pgid));
-                            self.entries.insert(child, inode);
-                            //self.entries.insert(
-                            //);
-                        }
-                    }
-                    // dbg!(&reply);
+            };
+            for (inode, next, kind, name) in entries {
+                if reply.add(inode, next, kind, &name) {
+                    //the reply is full
                     reply.ok();
+                    return;
                 }
+                offset = next;
+            }
+            if complete {
+                break;
             }
         }
+        reply.ok();
     }
 
-    fn readdirplus(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, reply: ReplyDirectoryPlus) {
-        reply.ok()
+    fn readdirplus(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectoryPlus) {
+        dbg!("readdirplus");
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
+        let mut offset = offset;
+        loop {
+            let (entries, complete) = match self.list_dir(ino, offset) {
+                Ok(listing) => listing,
+                Err(e) => {
+                    reply.error(e);
+                    return;
+                }
+            };
+            for (inode, next, _, name) in entries {
+                let attr = match inode {
+                    1 => Some(ROOT),
+                    _ => self.inode_file_attrs.get(&inode).copied(),
+                };
+                if let Some(attr) = attr {
+                    let attr = self.presented_attr(&attr);
+                    if reply.add(inode, next, &name, &self.entry_ttl(inode), &attr, 0) {
+                        reply.ok();
+                        return;
+                    }
+                }
+                offset = next;
+            }
+            if complete {
+                break;
+            }
+        }
+        reply.ok();
     }
 
     fn releasedir(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _flags: i32, reply: ReplyEmpty) {
@@ -676,29 +2714,93 @@ impl Filesystem for ByteaFileSystem {
     }
 
     fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
-        reply.error(ENOSYS);
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
+        let table = match self.file_inodes.get(&_ino) {
+            Some((table, _)) => Some(table),
+            None => self.table_dir_inodes.get_by_left(&_ino).and_then(|name| self.tables.get(name)),
+        };
+        let tables = table_names(&self.tables);
+        match self.quotas.space(&mut self.db_client, &mut self.statements, &tables, table.map(|table| table.quota()).as_ref()) {
+            Ok(space) => reply.statfs(space.blocks, space.free_blocks, space.free_blocks, space.files, space.free_files,
+                quota::BLOCK_SIZE as u32, 255, quota::BLOCK_SIZE as u32),
+            Err(e) => {
+                dbg!(e);
+                reply.error(EIO);
+            }
+        }
     }
 
     fn setxattr(&mut self, _req: &Request<'_>, _ino: u64, _name: &OsStr, _value: &[u8], _flags: i32, _position: u32, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
+        dbg!("setxattr", _name);
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
+        match self.set_xattr(&Caller::of(_req), _ino, _name, Some(_value), _flags) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
     }
 
     fn getxattr(&mut self, _req: &Request<'_>, _ino: u64, _name: &OsStr, _size: u32, reply: ReplyXattr) {
         dbg!("getxattr");
-        reply.error(ENODATA);
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
+        match self.get_xattr(_ino, _name) {
+            Ok(value) => xattr::reply(reply, _size, &value),
+            Err(e) => reply.error(e),
+        }
     }
 
     fn listxattr(&mut self, _req: &Request<'_>, _ino: u64, _size: u32, reply: ReplyXattr) {
-        reply.size(0);
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
+        match self.list_xattr(_ino) {
+            Ok(names) => xattr::reply(reply, _size, &names),
+            Err(e) => reply.error(e),
+        }
     }
 
     fn removexattr(&mut self, _req: &Request<'_>, _ino: u64, _name: &OsStr, reply: ReplyEmpty) {
-        reply.ok();
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
+        match self.set_xattr(&Caller::of(_req), _ino, _name, None, 0) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
     }
 
     fn access(&mut self, _req: &Request<'_>, _ino: u64, _mask: i32, reply: ReplyEmpty) {
         dbg!("access");
-        reply.ok()
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
+        let attr = match _ino {
+            1 => ROOT,
+            _ => match self.inode_file_attrs.get(&_ino) {
+                Some(attr) => *attr,
+                None => {
+                    reply.error(ENOENT);
+                    return;
+                }
+            },
+        };
+        let attr = self.presented_attr(&attr);
+        if permissions::allowed(&attr, _req.uid(), _req.gid(), _mask) {
+            reply.ok()
+        } else {
+            reply.error(EACCES)
+        }
     }
 
 
@@ -707,8 +2809,10 @@ impl Filesystem for ByteaFileSystem {
     fn mknod(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, _mode: u32, _umask: u32, _rdev: u32, reply: ReplyEntry) {
 
         match  self.create_internal( _req, _parent, _name) {
-            Ok(r) =>
-                reply.entry(&TTL, &ByteaFileSystem::file_attr(r, 0, Some(SystemTime::now()), Some(SystemTime::now())), 0),
+            Ok(r) => {
+                let attr = self.presented_attr(&ByteaFileSystem::file_attr(r, 0, Some(SystemTime::now()), Some(SystemTime::now())));
+                reply.entry(&self.entry_ttl(r), &attr, 0)
+            }
             Err(e) =>
                 reply.error(e)
 
@@ -717,47 +2821,68 @@ impl Filesystem for ByteaFileSystem {
     }
     fn create(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, _mode: u32, _umask: u32, _flags: i32, reply: ReplyCreate) {
         dbg!("create");
-        //create the file
-        //get parent to know what table it is
-        if parent == 1 {
-            //can't create a file at the top level
-            reply.error(ENOENT);
-            return;
-        }
-        if let Some(table_name) = self.table_dir_inodes.get_by_left(&parent) {
-            if let Some(table) = self.tables.get(table_name) {
-                if table.read_only {
-                    reply.error(EROFS);
-                    return;
+        match self.create_internal(_req, parent, name) {
+            Ok(inode) => {
+                let mut open_flags = self.cache_flags(inode);
+                let mut content = None;
+                if let Some((table, _)) = self.file_inodes.get(&inode)
+                    && table.is_function_backed() {
+                    content = Some(Vec::new());
+                    open_flags |= consts::FOPEN_DIRECT_IO;
                 }
-                self.next_inode += 1;
-                let inode = self.next_inode;
-                //insert the new record into the db with no data (create is basicly touch)
-                let name = name.to_str().unwrap();
-                let query = format!("insert into {} ({}) values ($1) returning {}", table_name, table.name_field.as_ref().unwrap(), &table.id_field);
-                let id = self.db_client.query_one(query.as_str(), &[&name, ]);
-                if id.is_err() {
-                    reply.error(ENOSYS);
-                } else {
-                    let id = id.unwrap().get::<usize, i32>(0) as u64;
-                    self.file_inodes.insert(inode, (table.clone(), PgId {
-                        table_inode: parent,
-                        pg_id: id,
-                    }));
-                    self.entries.insert(ChildNode { parent, name: name.to_string() }, inode);
-                    self.inode_file_attrs.insert(inode, ByteaFileSystem::file_attr(inode, 0, Some(SystemTime::now()), Some(SystemTime::now())));
-                    reply.created(&TTL, &ByteaFileSystem::file_attr(inode, 0, Some(SystemTime::now()), Some(SystemTime::now())), 0, 0, 0);
+                let fh = self.open_handle(inode, true, content);
+                if let Err(e) = self.begin_transaction_if_configured(inode, fh).and_then(|_| self.record_version_if_new(inode)) {
+                    self.open_files.remove(&fh);
+                    let _ = self.end_transaction(inode, fh);
+                    reply.error(e);
+                    return;
                 }
+                self.stage_if_configured(inode);
+                let attr = self.presented_attr(&ByteaFileSystem::file_attr(inode, 0, Some(SystemTime::now()), Some(SystemTime::now())));
+                reply.created(&self.entry_ttl(inode), &attr, 0, fh, open_flags)
             }
+            Err(e) =>
+                reply.error(e)
         }
     }
 
     fn getlk(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: i32, _pid: u32, reply: ReplyLock) {
-        reply.error(ENOSYS);
+        let Some(locks) = self.locks.as_ref() else {
+            reply.error(ENOSYS);
+            return;
+        };
+        let Some(key) = self.lock_key(_ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let lock = RangeLock { owner: _lock_owner, start: _start, end: _end, typ: _typ, pid: _pid };
+        match locks.test(_ino, &key, &lock) {
+            Ok(Some(conflict)) => reply.locked(conflict.start, conflict.end, conflict.typ, conflict.pid),
+            Ok(None) => reply.locked(_start, _end, F_UNLCK, 0),
+            Err(e) => reply.error(e),
+        }
     }
 
     fn setlk(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _lock_owner: u64, _start: u64, _end: u64, _typ: i32, _pid: u32, _sleep: bool, reply: ReplyEmpty) {
-        reply.error(ENOSYS);
+        dbg!("setlk", _ino, _lock_owner, _start, _end, _typ, _sleep);
+        let Some(locks) = self.locks.as_ref() else {
+            reply.error(ENOSYS);
+            return;
+        };
+        let Some(key) = self.lock_key(_ino) else {
+            reply.error(ENOENT);
+            return;
+        };
+        let lock = RangeLock { owner: _lock_owner, start: _start, end: _end, typ: _typ, pid: _pid };
+        if _sleep {
+            //replies from another thread if it has to wait
+            locks.set_and_wait(_ino, key, lock, reply);
+            return;
+        }
+        match locks.set(_ino, &key, &lock) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
     }
 
     fn bmap(&mut self, _req: &Request<'_>, _ino: u64, _blocksize: u32, _idx: u64, reply: ReplyBmap) {
@@ -768,6 +2893,15 @@ impl Filesystem for ByteaFileSystem {
         reply.error(ENOSYS);
     }
 
+    fn poll(&mut self, _req: &Request<'_>, ino: u64, fh: u64, ph: PollHandle, _events: u32, _flags: u32, reply: ReplyPoll) {
+        if self.channel_name(ino).is_some() {
+            self.channel_dir.as_ref().unwrap().hub.poll(fh, ph, reply);
+        } else {
+            //everything else is always ready
+            reply.poll((POLLIN | POLLOUT) as u32);
+        }
+    }
+
     fn fallocate(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _offset: i64, _length: i64, _mode: i32, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
@@ -776,8 +2910,20 @@ impl Filesystem for ByteaFileSystem {
         reply.error(ENOSYS);
     }
 
-    fn copy_file_range(&mut self, _req: &Request<'_>, _ino_in: u64, _fh_in: u64, _offset_in: i64, _ino_out: u64, _fh_out: u64, _offset_out: i64, _len: u64, _flags: u32, reply: ReplyWrite) {
-        reply.error(ENOSYS);
+    fn copy_file_range(&mut self, _req: &Request<'_>, ino_in: u64, _fh_in: u64, offset_in: i64, ino_out: u64, _fh_out: u64,
+                       offset_out: i64, len: u64, _flags: u32, reply: ReplyWrite) {
+        dbg!("copy_file_range", len);
+        if let Err(e) = self.start_request(_req) {
+            reply.error(e);
+            return;
+        }
+        match self.copy_range(ino_in, offset_in as u64, ino_out, offset_out as u64, len) {
+            Ok(copied) => {
+                self.note_write(Caller::of(_req), ino_out, copied as usize);
+                reply.written(copied as u32);
+            }
+            Err(e) => reply.error(e),
+        }
     }
 }
 
@@ -799,11 +2945,19 @@ impl log::Log for ConsoleLogger {
 static LOGGER: ConsoleLogger = ConsoleLogger;
 
 
+#[allow(clippy::expect_fun_call)]
 fn main() {
     log::set_logger(&LOGGER).unwrap();
     log::set_max_level(log::LevelFilter::Debug);
 
-    let args: Vec<String> = env::args().collect();
+    //usage: pgfs [install-triggers] [--snapshot[=<id>]] [--as-of=<timestamp>] [config file]
+    let mut args: Vec<String> = env::args().collect();
+    let snapshot_arg = args.iter().position(|arg| arg.starts_with("--snapshot")).map(|i| args.remove(i));
+    let as_of = args.iter().position(|arg| arg.starts_with("--as-of=")).map(|i| args.remove(i)["--as-of=".len()..].to_string());
+    let install_triggers = args.len() > 1 && args[1] == "install-triggers";
+    if install_triggers {
+        args.remove(1);
+    }
     let config_file_location = if args.len() < 2 {
         "config.toml".to_string()
     } else {
@@ -813,38 +2967,193 @@ fn main() {
     use std::fs;
     let config_string = fs::read_to_string(config_file_location).unwrap();
 
-    let cfg: PgfsConfig = PgfsConfig::new(&config_string).unwrap();
+    let mut cfg: PgfsConfig = PgfsConfig::new(&config_string).unwrap();
+    if let Some(snapshot_arg) = snapshot_arg {
+        cfg.snapshot = true;
+        if let Some(id) = snapshot_arg.strip_prefix("--snapshot=") {
+            cfg.snapshot_id = Some(id.to_string());
+        }
+        cfg.check_transaction_mode().unwrap();
+    }
 
     dbg!(&cfg);
     let mountpoint = cfg.mountpoint;
-    let mut options = vec![MountOption::RW, MountOption::FSName("pgtest".to_string())];
+    let read_only_mount = cfg.snapshot || as_of.is_some();
+    let mut options = vec![if read_only_mount { MountOption::RO } else { MountOption::RW }, MountOption::FSName("pgtest".to_string())];
+    if cfg.roles.is_some() {
+        //roles only make sense if other users can use the mount
+        options.push(MountOption::AllowOther);
+    }
     options.push(MountOption::AutoUnmount);
 
 
     let db_string = cfg.connection_string.expect("Database connection details missing");
-    let  client = Client::connect(&db_string, NoTls).expect(format!("Unable to open a connection to database {}", db_string).as_str());
+    let mut db_config: postgres::Config = db_string.parse().expect("Unable to understand the database connection string");
+    if cfg.pgbouncer_transaction_mode {
+        //a SET would only last for one transaction, so ask for the lock timeout when connecting
+        let options = format!("{} -c lock_timeout={}", db_config.get_options().unwrap_or_default(), transaction::LOCK_TIMEOUT);
+        db_config.options(options.trim_start());
+    }
+    let mut client = db_config.connect(NoTls).expect(format!("Unable to open a connection to database {}", db_string).as_str());
+    if install_triggers {
+        let channel = cfg.change_channel.as_deref().unwrap_or(changes::DEFAULT_CHANNEL);
+        let table_configs: Vec<&TableConfig> = cfg.table_config.values().collect();
+        changes::install_triggers(&mut client, &table_configs, channel).expect("Unable to install triggers");
+        println!("Installed triggers notifying channel {} on {} tables", channel, table_configs.len());
+        return;
+    }
+    let mut statements = Statements::new(cfg.pgbouncer_transaction_mode);
+    let own_pid: i32 = statements.query_opt(&mut client, "select pg_backend_pid()", &[]).ok().flatten().map_or(0, |row| row.get(0));
+    //files belong to whoever owns the mountpoint, unless a table says otherwise
+    let owner = fs::metadata(&mountpoint).map(|metadata| (metadata.uid(), metadata.gid())).unwrap_or((0, 0));
     let mut tables = vec![];
     cfg.table_config.iter().for_each(|(name, fs)| {
         dbg!(name);
+        let live_value = fs.live_value.clone().unwrap_or_else(|| "null".to_string());
         tables.push(Table {
             table_name: fs.table_name.clone(),
             id_field: fs.id_field.clone(),
-            length_field: fs.length_field.clone(),
             bytea_field: fs.data_field.clone(),
             name_field: Some(fs.name_field.clone()),
-            query_string: fs.data_query.to_string(),
+            query_string: match fs.deleted_field.as_ref() {
+                Some(deleted_field) => trash::rows_query(&fs.data_query, &fs.table_name, &fs.id_field, deleted_field, &live_value, false),
+                None => fs.data_query.to_string(),
+            },
             data_query_string: format!("select substring({}, $2, $3) from {} where ({}=$1);", fs.data_field, fs.table_name, fs.id_field),
             read_only: false,
-            delete_query_string: None,
+            delete_query_string: fs.delete_query.clone(),
             created_field:fs.created_date_field.clone(),
             modified_field:fs.modified_date_field.clone(),
+            read_function_string: fs.read_function.as_ref().map(|f| function::function_query_string(f, &fs.function_params, false)),
+            write_function_string: fs.write_function.as_ref().map(|f| function::function_query_string(f, &fs.function_params, true)),
+            function_params: fs.function_params.clone(),
+            write_transactions: fs.write_transactions,
+            version_expr: match fs.version_field.as_ref() {
+                Some(version_field) => Some(format!("coalesce({}, 0)::bigint", version_field)),
+                None if fs.optimistic_locking => Some("xmin::text::bigint".to_string()),
+                None => None,
+            },
+            version_field: fs.version_field.clone(),
+            conflict_copy: fs.conflict_copy,
+            history_table: fs.history_table.clone(),
+            deleted_field: fs.deleted_field.clone(),
+            deleted_value: fs.deleted_value.clone().unwrap_or_else(|| "now()".to_string()),
+            live_value: live_value.clone(),
+            trash_query_string: fs.deleted_field.as_ref()
+                .map(|deleted_field| trash::rows_query(&fs.data_query, &fs.table_name, &fs.id_field, deleted_field, &live_value, true)),
+            temporal: match (fs.valid_from_field.as_ref(), fs.valid_to_field.as_ref()) {
+                (Some(valid_from_field), Some(valid_to_field)) => Some(Temporal {
+                    valid_from_field: valid_from_field.clone(),
+                    valid_to_field: valid_to_field.clone(),
+                    history_table: fs.temporal_history_table.clone(),
+                    query_string: String::new(),
+                    data_query_string: String::new(),
+                    trash_query_string: None,
+                }),
+                _ => None,
+            },
+            as_of: None,
+            uid: fs.uid.unwrap_or(owner.0),
+            gid: fs.gid.unwrap_or(owner.1),
+            mode: fs.mode.unwrap_or(permissions::DEFAULT_MODE),
+            mode_field: fs.mode_field.clone(),
+            owner_field: fs.owner_field.clone(),
+            group_field: fs.group_field.clone(),
+            xattr_fields: fs.xattr_fields.clone(),
+            xattr_column: fs.xattr_column.clone(),
+            quota_bytes: fs.quota_bytes,
+            quota_files: fs.quota_files,
+            write_cache: fs.write_cache,
+            whole_file: fs.whole_file,
+            entry_ttl: fs.entry_ttl.unwrap_or(TTL),
+            attr_ttl: fs.attr_ttl.unwrap_or(TTL),
+            keep_cache: fs.keep_cache,
+            direct_io: fs.direct_io,
         });
     });
-    let filesystem = ByteaFileSystem::new(
+    for table in tables.iter_mut() {
+        //keep the queries for going back in time, and show only current rows otherwise
+        if let Some(temporal) = table.temporal.as_mut() {
+            temporal.query_string = table.query_string.clone();
+            temporal.data_query_string = table.data_query_string.clone();
+            temporal.trash_query_string = table.trash_query_string.clone();
+            table.query_string = temporal.current_query(&table.query_string, &table.table_name);
+            table.data_query_string = temporal.current_query(&table.data_query_string, &table.table_name);
+            table.trash_query_string = table.trash_query_string.as_ref().map(|query| temporal.current_query(query, &table.table_name));
+        }
+    }
+    if let Some(timestamp) = as_of.as_deref() {
+        temporal::check_timestamp(&mut client, &mut statements, timestamp).expect("Unable to understand the --as-of timestamp");
+        tables = tables.into_iter().map(|table| table.as_of(timestamp).unwrap_or(table)).collect();
+    }
+    if tables.iter().any(|table| table.write_transactions) && !cfg.pgbouncer_transaction_mode {
+        //never wait forever for a row locked by one of our own write transactions
+        client.batch_execute(&format!("SET lock_timeout = '{}'", transaction::LOCK_TIMEOUT)).expect("Unable to set lock timeout");
+    }
+    let snapshot_id = match cfg.snapshot {
+        true => {
+            let id = snapshot::start(&mut client, cfg.snapshot_id.as_deref()).expect("Unable to start snapshot transaction");
+            println!("Reading from snapshot {}", id);
+            Some(id)
+        }
+        false => None,
+    };
+    let mut filesystem = ByteaFileSystem::new(
         "pgfs",
         client,
+        &db_string,
         tables,
     );
-    fuser::mount2(filesystem, mountpoint, &options).unwrap();
+    filesystem.set_owner(owner.0, owner.1);
+    filesystem.use_statements(statements);
+    if let Some(id) = snapshot_id {
+        filesystem.use_snapshot(id);
+    }
+    if let Some(capacity) = cfg.capacity {
+        filesystem.set_capacity(capacity);
+    }
+    if !cfg.file_locks {
+        filesystem.disable_locks();
+    }
+    filesystem.set_read_cache(cfg.read_cache.clone());
+    filesystem.set_write_cache(cfg.write_cache.clone());
+    filesystem.set_staging(cfg.staging.clone());
+    if let Some(audit_table) = &cfg.audit_table {
+        filesystem.enable_audit(audit_table);
+    }
+    if let Some(roles) = cfg.roles.take() {
+        filesystem.enable_roles(roles);
+    }
+    if let Some(channels) = cfg.channels {
+        let hub = ChannelHub::start(&db_string, &channels.names).expect("Unable to open a connection to listen for notifications");
+        filesystem.enable_channels(&channels.directory, &channels.names, hub);
+    }
+    let change_feed = ChangeFeed::default();
+    if cfg.snapshot && cfg.change_channel.is_some() {
+        //nothing changes in a snapshot
+        cfg.change_channel = None;
+    }
+    if cfg.change_channel.is_some() {
+        filesystem.enable_change_feed(change_feed.clone());
+    }
+    let mut own_pids = vec![own_pid];
+    if cfg.async_backend {
+        if filesystem.roles.is_some() || cfg.snapshot {
+            log::warn!("async_backend is not used with [roles] or [snapshot]");
+        } else {
+            let pipeline = Pipeline::connect(&db_string, cfg.pgbouncer_transaction_mode).expect("Unable to start the async backend");
+            own_pids.push(pipeline.writer_pid());
+            filesystem.enable_async_backend(pipeline);
+        }
+    }
+    let table_dirs: HashMap<String, Inode> = filesystem.table_dir_inodes.iter().map(|(inode, name)| (name.clone(), *inode)).collect();
+    let rows = filesystem.rows.clone();
+
+    let mut session = Session::new(filesystem, mountpoint, &options).unwrap();
+    if let Some(channel) = cfg.change_channel {
+        change_feed.start(&db_string, &channel, table_dirs, own_pids, rows, session.notifier())
+            .expect("Unable to open a connection to listen for changes");
+    }
+    session.run().unwrap();
 
 }
@@ -0,0 +1,136 @@
+//! File permissions from PostgreSQL privileges.
+//!
+//! Each table's files and directory are owned by the table's `uid` and `gid` (by default the owner of the
+//! mountpoint) and have its `mode` (by default `0o755`, with directories also searchable wherever they
+//! are readable). Bits are then taken away for whatever the current role (the mapped role, when roles
+//! are mapped) may not do: files are only readable with SELECT on the table and only writable with UPDATE
+//! on its data column, and the directory is only writable with INSERT or DELETE on the table. So `ls -l`
+//! shows a table the role can't change as read only, and `access()` gives the same answer as trying.
+//!
+//! Tables with a `mode_field`, `owner_field` or `group_field` keep each file's mode, uid or gid in its row,
+//! set by `chmod` and `chown`. Where these are null the table's settings apply.
+//!
+//! Privileges are looked up at most once every [`PRIVILEGE_TTL`] for each table and role. The kernel keeps
+//! a directory's attributes for a short while whoever asked for them (a file's aren't kept with mapped
+//! roles), so `ls -ld` can briefly show what another user may do, but `access()` is always answered for the
+//! caller.
+
+use crate::statements::Statements;
+use fuser::FileAttr;
+use libc::{R_OK, W_OK, X_OK};
+use postgres::Client;
+use std::time::Duration;
+
+pub const DEFAULT_MODE: u16 = 0o755;
+
+/// How long privileges are remembered, so that GRANTs and REVOKEs show up without remounting
+pub const PRIVILEGE_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Privileges {
+    pub read: bool,
+    pub write: bool,
+    pub create: bool,
+}
+
+impl Privileges {
+    pub const ALL: Privileges = Privileges { read: true, write: true, create: true };
+}
+
+/// Mode and owner kept in a file's row, where the table has columns for them
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Ownership {
+    pub mode: Option<u16>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+/// The current role's privileges on a table
+pub fn privileges(client: &mut Client, statements: &mut Statements, table_name: &str, data_field: &str) -> Result<Privileges, postgres::Error> {
+    let row = statements.query_opt(client, "select has_table_privilege($1, 'SELECT'), \
+        has_table_privilege($1, 'UPDATE') or exists(select 1 from pg_attribute where attrelid = $1::regclass \
+            and attname = $2 and not attisdropped and has_column_privilege($1, $2, 'UPDATE')), \
+        has_table_privilege($1, 'INSERT') or has_table_privilege($1, 'DELETE')", &[&table_name, &data_field])?;
+    Ok(row.map_or(Privileges { read: false, write: false, create: false },
+        |row| Privileges { read: row.get(0), write: row.get(1), create: row.get(2) }))
+}
+
+/// Permission bits for a file, from the bits it would otherwise have and the table's mode
+pub fn file_perm(perm: u16, mode: u16, privileges: &Privileges) -> u16 {
+    perm & mode & mask(privileges.read, privileges.write)
+}
+
+pub fn dir_perm(perm: u16, mode: u16, privileges: &Privileges) -> u16 {
+    //a directory can be searched by whoever can read it
+    let mode = mode | ((mode & 0o444) >> 2);
+    perm & mode & mask(privileges.read, privileges.create)
+}
+
+fn mask(read: bool, write: bool) -> u16 {
+    (if read { 0o555 } else { 0 }) | (if write { 0o222 } else { 0 })
+}
+
+/// Whether a process may access a file in the ways in `mask` (as passed to `access()`), going by its
+/// permission bits
+pub fn allowed(attr: &FileAttr, uid: u32, gid: u32, mask: i32) -> bool {
+    let bits = if uid == 0 {
+        //root gets whatever anyone gets, since the privileges have been applied to every class
+        (attr.perm | attr.perm >> 3 | attr.perm >> 6) & 0o7
+    } else if uid == attr.uid {
+        (attr.perm >> 6) & 0o7
+    } else if gid == attr.gid {
+        (attr.perm >> 3) & 0o7
+    } else {
+        attr.perm & 0o7
+    };
+    [(R_OK, 0o4), (W_OK, 0o2), (X_OK, 0o1)].iter()
+        .all(|(wanted, bit)| mask & wanted == 0 || bits & bit != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fuser::FileType;
+    use std::time::UNIX_EPOCH;
+
+    fn attr(perm: u16, uid: u32, gid: u32) -> FileAttr {
+        FileAttr { ino: 2, size: 0, blocks: 0, atime: UNIX_EPOCH, mtime: UNIX_EPOCH, ctime: UNIX_EPOCH, crtime: UNIX_EPOCH,
+            kind: FileType::RegularFile, perm, nlink: 1, uid, gid, rdev: 0, blksize: 512, flags: 0 }
+    }
+
+    #[test]
+    fn file_perm_takes_away_what_the_role_cant_do() {
+        let read_only = Privileges { read: true, write: false, create: true };
+        let none = Privileges { read: false, write: false, create: false };
+        assert_eq!(file_perm(0o777, 0o644, &Privileges::ALL), 0o644);
+        assert_eq!(file_perm(0o777, 0o755, &read_only), 0o555);
+        assert_eq!(file_perm(0o666, 0o755, &read_only), 0o444);
+        assert_eq!(file_perm(0o777, 0o755, &none), 0);
+    }
+
+    #[test]
+    fn dir_perm_is_searchable_where_readable() {
+        let no_create = Privileges { read: true, write: true, create: false };
+        assert_eq!(dir_perm(0o777, 0o644, &Privileges::ALL), 0o755);
+        assert_eq!(dir_perm(0o777, 0o640, &no_create), 0o550);
+    }
+
+    #[test]
+    fn allowed_uses_the_callers_class() {
+        let file = attr(0o640, 1000, 100);
+        assert!(allowed(&file, 1000, 1000, R_OK | W_OK));
+        assert!(allowed(&file, 1001, 100, R_OK));
+        assert!(!allowed(&file, 1001, 100, W_OK));
+        assert!(!allowed(&file, 1002, 1002, R_OK));
+        assert!(allowed(&file, 1002, 1002, 0));
+        //an owner is held to the owner bits even if the group bits allow more
+        assert!(!allowed(&attr(0o070, 1000, 100), 1000, 100, R_OK));
+    }
+
+    #[test]
+    fn allowed_gives_root_what_anyone_gets() {
+        assert!(allowed(&attr(0o604, 1000, 100), 0, 0, R_OK | W_OK));
+        assert!(!allowed(&attr(0o444, 1000, 100), 0, 0, W_OK));
+        assert!(!allowed(&attr(0o644, 1000, 100), 0, 0, X_OK));
+    }
+}
@@ -0,0 +1,318 @@
+//! Async storage backend.
+//!
+//! Callbacks normally run their queries on the FUSE thread with the blocking `postgres` client, so the
+//! whole session waits on each one. With `async_backend = true` at the top level of the config, reads of
+//! file content are instead handed to a tokio runtime with tokio-postgres connections of its own, and
+//! answered from the task once their data arrives, while the session goes on to the next request (the
+//! kernel sends several reads of a file at once). The queries of all those tasks share one connection
+//! and are pipelined on it: each is sent without waiting for the replies to those before it.
+//!
+//! With the read cache, a read which misses sends the blocks it needs and its readahead window as two
+//! independent queries, and is answered as soon as the first returns. The readahead window is topped up
+//! as reads move through it rather than only on a miss, and reads needing blocks which are already on
+//! their way wait for them rather than asking again. Writing out a file's buffered ranges sends all of
+//! their `overlay()` updates at once, in a transaction on a second connection, rather than one at a time.
+//!
+//! ```toml
+//! async_backend = true
+//! ```
+//!
+//! Everything else still runs on the FUSE thread. Reads by a handle writing in a transaction use its
+//! connection as before, and the backend isn't used at all with `[roles]` or `[snapshot]`, as their
+//! connections see different data to a new one. Tables with a version check (`version_field` or
+//! `optimistic_locking`) still write ranges one at a time.
+
+use crate::Inode;
+use crate::block_cache::{BlockCache, BlockKey};
+use crate::{roles, statements};
+use fuser::ReplyData;
+use futures_util::future::{self, BoxFuture, FutureExt, Shared};
+use libc::EIO;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::runtime::{Builder, Runtime};
+use tokio_postgres::types::{ToSql, Type};
+use tokio_postgres::{Client, Error, NoTls, Row, Statement};
+
+/// Threads for the runtime. They have little to do but wait on the database.
+const WORKER_THREADS: usize = 2;
+
+/// Data read from a file, or why it couldn't be
+type Fetched = Result<Arc<Vec<u8>>, Arc<Error>>;
+
+/// A query for a range of a file, which any number of reads can wait on
+#[derive(Clone)]
+struct Fetch {
+    id: u64,
+    ///the file's generation in the cache when it was sent
+    generation: u64,
+    start: u64,
+    data: Shared<BoxFuture<'static, Fetched>>,
+}
+
+/// Where a read gets a block from
+enum Source {
+    Cached(Vec<u8>),
+    Fetch(Fetch),
+}
+
+/// Statements prepared on a connection
+#[derive(Default)]
+struct Prepared(Mutex<HashMap<String, Statement>>);
+
+impl Prepared {
+    async fn get(&self, client: &Client, sql: &str) -> Result<Statement, Error> {
+        let cached = self.0.lock().unwrap().get(sql).cloned();
+        if let Some(statement) = cached {
+            return Ok(statement);
+        }
+        let statement = client.prepare(sql).await?;
+        let mut prepared = self.0.lock().unwrap();
+        if prepared.len() >= statements::CACHE_SIZE {
+            prepared.clear();
+        }
+        prepared.insert(sql.to_string(), statement.clone());
+        Ok(statement)
+    }
+}
+
+struct Connection {
+    client: Client,
+    prepared: Prepared,
+    ///send statements unprepared, for a pooler in transaction mode
+    unnamed: bool,
+}
+
+impl Connection {
+    /// Connect, driving the connection on the runtime this is run on
+    async fn open(connection_string: &str, unnamed: bool) -> Result<Connection, Error> {
+        let (client, connection) = tokio_postgres::connect(connection_string, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                log::warn!("Lost an async backend connection: {}", e);
+            }
+        });
+        Ok(Connection { client, prepared: Prepared::default(), unnamed })
+    }
+
+    /// Run a statement, prepared unless statements are sent unprepared. If `statement` is given it has
+    /// been prepared already, and the statement is sent as soon as the future is first polled.
+    async fn run(&self, sql: &str, statement: Option<&Statement>, params: Vec<&(dyn ToSql + Sync)>) -> Result<Vec<Row>, Error> {
+        if self.unnamed {
+            let typed: Vec<(&(dyn ToSql + Sync), Type)> = params.iter().map(|param| (*param, statements::parameter_type(*param))).collect();
+            return self.client.query_typed(sql, &typed).await;
+        }
+        let statement = match statement {
+            Some(statement) => statement.clone(),
+            None => self.prepared.get(&self.client, sql).await?,
+        };
+        self.client.query(&statement, &params).await
+    }
+
+    /// Read `length` bytes of a row's data from `start` with the table's data query
+    async fn fetch(&self, sql: &str, id: i32, start: u64, length: u64) -> Result<Vec<u8>, Error> {
+        let rows = self.run(sql, None, vec![&id, &(1 + start as i32), &(length as i32)]).await?;
+        Ok(rows.first().and_then(|row| row.get::<usize, Option<Vec<u8>>>(0)).unwrap_or_default())
+    }
+}
+
+pub struct Pipeline {
+    runtime: Runtime,
+    ///for reads, which share it
+    reader: Arc<Connection>,
+    ///for writes, which are made from the FUSE thread
+    writer: Connection,
+    writer_pid: i32,
+    ///fetches on their way, by the blocks they will fill
+    pending: Arc<Mutex<HashMap<BlockKey, Fetch>>>,
+    next_fetch: AtomicU64,
+}
+
+impl Pipeline {
+    pub fn connect(connection_string: &str, unnamed: bool) -> Result<Pipeline, Box<dyn std::error::Error>> {
+        let runtime = Builder::new_multi_thread().worker_threads(WORKER_THREADS).enable_all().build()?;
+        let (reader, writer, writer_pid) = runtime.block_on(async {
+            let reader = Connection::open(connection_string, unnamed).await?;
+            let writer = Connection::open(connection_string, unnamed).await?;
+            let pid: i32 = writer.run("select pg_backend_pid()", None, vec![]).await?
+                .first().map(|row| row.get(0)).unwrap_or(0);
+            Ok::<_, Error>((reader, writer, pid))
+        })?;
+        Ok(Pipeline {
+            runtime,
+            reader: Arc::new(reader),
+            writer,
+            writer_pid,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            next_fetch: AtomicU64::new(0),
+        })
+    }
+
+    /// The backend pid of the connection writes are made on, whose change notifications are our own
+    pub fn writer_pid(&self) -> i32 {
+        self.writer_pid
+    }
+
+    /// Read `size` bytes at `offset` of a row with the table's data query `sql`, replying when they arrive
+    pub fn read(&self, sql: String, id: i32, offset: u64, size: u32, reply: ReplyData) {
+        let reader = self.reader.clone();
+        self.runtime.spawn(async move {
+            match reader.fetch(&sql, id, offset, size as u64).await {
+                Ok(data) => reply.data(&data),
+                Err(e) => {
+                    log::warn!("Unable to read row {}: {}", id, e);
+                    reply.error(roles::errno(&e, EIO))
+                }
+            }
+        });
+    }
+
+    /// Read through the block cache, as `BlockCache::read` does, fetching missing blocks and the
+    /// readahead window as separate queries and replying once the blocks the read needs have arrived
+    #[allow(clippy::too_many_arguments)]
+    pub fn read_cached(&self, cache: &BlockCache, ino: Inode, file_size: u64, offset: u64, size: u32, sql: String, id: i32,
+                       reply: ReplyData) {
+        let end = file_size.min(offset + size as u64);
+        if offset >= end {
+            reply.data(&[]);
+            return;
+        }
+        let config = cache.config().clone();
+        let block_size = config.block_size;
+        let (first, last) = (offset / block_size, (end - 1) / block_size);
+        let generation = cache.generation(ino);
+        let mut pending = self.pending.lock().unwrap();
+        let waiting = |pending: &HashMap<BlockKey, Fetch>, block: u64| {
+            pending.get(&(ino, block)).filter(|fetch| fetch.generation == generation).cloned()
+        };
+        let mut sources: Vec<Option<Source>> = (first..=last)
+            .map(|block| cache.get((ino, block)).map(Source::Cached).or_else(|| waiting(&pending, block).map(Source::Fetch)))
+            .collect();
+        let missing: Vec<u64> = (first..=last).filter(|block| sources[(block - first) as usize].is_none()).collect();
+        if let (Some(from), Some(to)) = (missing.first(), missing.last()) {
+            let (fetch_start, fetch_end) = if file_size <= config.whole_file {
+                (0, file_size)
+            } else {
+                (from * block_size, file_size.min((to + 1) * block_size))
+            };
+            let fetch = self.start_fetch(&mut pending, cache, ino, generation, fetch_start, fetch_end - fetch_start, &sql, id);
+            for source in sources.iter_mut().filter(|source| source.is_none()) {
+                *source = Some(Source::Fetch(fetch.clone()));
+            }
+        }
+        //top up the readahead window once half of it has been read
+        let mut readahead = None;
+        if file_size > config.whole_file {
+            let window_start = (last + 1) * block_size;
+            let window_end = file_size.min((window_start + config.readahead).div_ceil(block_size) * block_size);
+            let next = (last + 1..window_end.div_ceil(block_size))
+                .find(|block| !cache.contains((ino, *block)) && waiting(&pending, *block).is_none());
+            if let Some(block) = next
+                && block * block_size < window_start + config.readahead / 2 {
+                let fetch_start = block * block_size;
+                readahead = Some(self.start_fetch(&mut pending, cache, ino, generation, fetch_start, window_end - fetch_start, &sql, id));
+            }
+        }
+        drop(pending);
+        let sources: Vec<Source> = sources.into_iter().flatten().collect();
+        let answer = async move {
+            let blocks = future::join_all(sources.into_iter().enumerate().map(|(i, source)| async move {
+                match source {
+                    Source::Cached(data) => Ok(data),
+                    Source::Fetch(fetch) => fetch.data.await.map(|data| {
+                        let from = (((first + i as u64) * block_size).saturating_sub(fetch.start) as usize).min(data.len());
+                        data[from..(from + block_size as usize).min(data.len())].to_vec()
+                    }),
+                }
+            })).await;
+            let mut result = Vec::with_capacity((end - offset) as usize);
+            let mut position = offset;
+            for (i, block) in blocks.into_iter().enumerate() {
+                let bytes = match block {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        log::warn!("Unable to read {}: {}", ino, e);
+                        reply.error(roles::errno(&e, EIO));
+                        return;
+                    }
+                };
+                let block_start = (first + i as u64) * block_size;
+                let from = (position - block_start) as usize;
+                let to = ((end - block_start) as usize).min(bytes.len());
+                if from >= to {
+                    //the file was shorter than we thought
+                    break;
+                }
+                result.extend_from_slice(&bytes[from..to]);
+                position = block_start + to as u64;
+            }
+            reply.data(&result);
+        };
+        //the answer is polled first, so its query goes ahead of the readahead
+        self.runtime.spawn(future::join(answer, async move {
+            if let Some(fetch) = readahead {
+                let _ = fetch.data.await;
+            }
+        }));
+    }
+
+    /// Start reading a range of a file for the cache. It is sent when whichever read started it is
+    /// spawned, and counts as pending for its blocks until it is done.
+    #[allow(clippy::too_many_arguments)]
+    fn start_fetch(&self, pending: &mut HashMap<BlockKey, Fetch>, cache: &BlockCache, ino: Inode, generation: u64, start: u64,
+                   length: u64, sql: &str, id: i32) -> Fetch {
+        let fetch_id = self.next_fetch.fetch_add(1, Ordering::Relaxed);
+        let block_size = cache.config().block_size;
+        let blocks = start / block_size..(start + length).div_ceil(block_size);
+        let (reader, cache, all_pending, sql) = (self.reader.clone(), cache.clone(), self.pending.clone(), sql.to_string());
+        let fetched_blocks = blocks.clone();
+        let data = async move {
+            let result = reader.fetch(&sql, id, start, length).await;
+            if let Ok(data) = &result {
+                cache.insert_fetched(ino, generation, start, data);
+            }
+            let mut pending = all_pending.lock().unwrap();
+            for block in fetched_blocks {
+                if pending.get(&(ino, block)).is_some_and(|fetch| fetch.id == fetch_id) {
+                    pending.remove(&(ino, block));
+                }
+            }
+            result.map(Arc::new).map_err(Arc::new)
+        }.boxed().shared();
+        let fetch = Fetch { id: fetch_id, generation, start, data };
+        for block in blocks {
+            pending.insert((ino, block), fetch.clone());
+        }
+        fetch
+    }
+
+    /// Write ranges of a row with `sql`, an update taking the data, its 1-based offset, its length and the
+    /// row's id. The updates are all sent at once, in a transaction.
+    pub fn overlay_all(&self, sql: &str, id: i32, ranges: &[(u64, Vec<u8>)]) -> Result<(), Error> {
+        let writer = &self.writer;
+        self.runtime.block_on(async {
+            //prepared first, so nothing is waiting on it once the transaction has begun
+            let statement = match writer.unnamed {
+                true => None,
+                false => Some(writer.prepared.get(&writer.client, sql).await?),
+            };
+            let params: Vec<(&[u8], i32, i32)> = ranges.iter()
+                .map(|(offset, data)| (data.as_slice(), 1 + *offset as i32, data.len() as i32))
+                .collect();
+            let updates = params.iter()
+                .map(|(data, from, length)| writer.run(sql, statement.as_ref(), vec![data, from, length, &id]));
+            let (begun, updated, committed) = future::join3(
+                writer.client.batch_execute("BEGIN"),
+                future::join_all(updates),
+                writer.client.batch_execute("COMMIT"),
+            ).await;
+            begun?;
+            //if an update failed the COMMIT will have rolled back
+            for update in updated {
+                update?;
+            }
+            committed
+        })
+    }
+}
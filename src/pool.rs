@@ -0,0 +1,35 @@
+//! A small pool of extra connections to the database, for work which can't share the main connection
+//! (such as a transaction which stays open while a file is written).
+
+use postgres::{Client, NoTls};
+
+pub struct ConnectionPool {
+    connection_string: String,
+    spare: Vec<Client>,
+}
+
+impl ConnectionPool {
+    pub fn new(connection_string: &str) -> ConnectionPool {
+        ConnectionPool {
+            connection_string: connection_string.to_string(),
+            spare: vec![],
+        }
+    }
+
+    /// Take a spare connection, or open a new one if there are none
+    pub fn get(&mut self) -> Result<Client, postgres::Error> {
+        while let Some(client) = self.spare.pop() {
+            if !client.is_closed() {
+                return Ok(client);
+            }
+        }
+        Client::connect(&self.connection_string, NoTls)
+    }
+
+    /// Return a connection to the pool. It must not be in a transaction.
+    pub fn put(&mut self, client: Client) {
+        if !client.is_closed() {
+            self.spare.push(client);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Space reporting and quotas.
+//!
+//! `statfs` (and so `df`) reports the size of the database from `pg_database_size` and the number of rows
+//! in the mounted tables as the space and files used. The total is the top-level `capacity` if one is set,
+//! otherwise the used space plus [`DEFAULT_HEADROOM`], so that tools which check for space before writing
+//! aren't put off. For a path inside a table with a quota, the numbers are those of the table instead:
+//! the total size of its data column and its row count, against its quota.
+//!
+//! Tables can set `quota_bytes` and `quota_files`. Writes which would take the table's data past its
+//! quota fail with EDQUOT, as does creating a file past its file quota, and writes which would take the
+//! database past its `capacity` fail with ENOSPC. Function backed files aren't counted. Sizes can be given
+//! in bytes or with a K, M, G or T suffix:
+//!
+//! ```toml
+//! capacity = "50G"
+//!
+//! [scripts]
+//! quota_bytes = "100M"
+//! quota_files = 1000
+//! ```
+//!
+//! Files in a table's trash don't count against its quota. Usage is looked up at most once every
+//! [`USAGE_TTL`] and counted locally in between, so other applications writing at the same time can take a
+//! table a little past its quota.
+
+use crate::statements::Statements;
+use libc::{EDQUOT, ENOSPC};
+use postgres::Client;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+pub const BLOCK_SIZE: u64 = 4096;
+/// Free space reported when there is no capacity set
+pub const DEFAULT_HEADROOM: u64 = 1 << 40;
+/// Free files reported when there is no limit on them
+const FILES_HEADROOM: u64 = 1 << 32;
+pub const USAGE_TTL: Duration = Duration::from_secs(30);
+
+/// The limits on a table
+pub struct TableQuota<'a> {
+    pub table_name: &'a str,
+    pub data_field: &'a str,
+    pub bytes: Option<u64>,
+    pub files: Option<u64>,
+    ///condition on the rows which aren't in the trash, for tables with one
+    pub live_condition: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Usage {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+/// Space and files, as statfs reports them
+pub struct Space {
+    pub blocks: u64,
+    pub free_blocks: u64,
+    pub files: u64,
+    pub free_files: u64,
+}
+
+pub struct Quotas {
+    capacity: Option<u64>,
+    ///usage of the database (keyed by None) and of tables, and when it was looked up
+    usage: HashMap<Option<String>, (Instant, Usage)>,
+}
+
+impl Quotas {
+    pub fn new(capacity: Option<u64>) -> Quotas {
+        Quotas { capacity, usage: HashMap::new() }
+    }
+
+    /// Usage of the database, with the row counts of `tables` as the files used
+    fn database_usage(&mut self, client: &mut Client, statements: &mut Statements, tables: &[&str]) -> Result<Usage, postgres::Error> {
+        if let Some((checked, usage)) = self.usage.get(&None)
+            && checked.elapsed() < USAGE_TTL {
+            return Ok(*usage);
+        }
+        //estimated row counts, since counting every table would be slow
+        let row = statements.query_opt(client, "select pg_database_size(current_database()), \
+            (select coalesce(sum(greatest(c.reltuples, 0)), 0)::bigint from unnest($1::text[]) as t(name) \
+                join pg_class c on c.oid = to_regclass(t.name))", &[&tables])?;
+        let usage = usage_of(row);
+        self.usage.insert(None, (Instant::now(), usage));
+        Ok(usage)
+    }
+
+    fn table_usage(&mut self, client: &mut Client, statements: &mut Statements, table: &TableQuota) -> Result<Usage, postgres::Error> {
+        let key = Some(table.table_name.to_string());
+        if let Some((checked, usage)) = self.usage.get(&key)
+            && checked.elapsed() < USAGE_TTL {
+            return Ok(*usage);
+        }
+        let mut query = format!("select coalesce(sum(octet_length({})), 0)::bigint, count(*) from {}", table.data_field, table.table_name);
+        if let Some(live_condition) = &table.live_condition {
+            query.push_str(&format!(" where {}", live_condition));
+        }
+        let row = statements.query_opt(client, query.as_str(), &[])?;
+        let usage = usage_of(row);
+        self.usage.insert(key, (Instant::now(), usage));
+        Ok(usage)
+    }
+
+    /// Space for the whole mount, or for one table if it has a quota
+    pub fn space(&mut self, client: &mut Client, statements: &mut Statements, tables: &[&str], table: Option<&TableQuota>) -> Result<Space, postgres::Error> {
+        let database = self.database_usage(client, statements, tables)?;
+        let total_bytes = self.capacity.unwrap_or(database.bytes + DEFAULT_HEADROOM);
+        let (used, total_bytes, total_files) = match table {
+            Some(table) if table.bytes.is_some() || table.files.is_some() => {
+                let usage = self.table_usage(client, statements, table)?;
+                (usage, table.bytes.unwrap_or(total_bytes), table.files.unwrap_or(usage.files + FILES_HEADROOM))
+            }
+            _ => (database, total_bytes, database.files + FILES_HEADROOM),
+        };
+        Ok(Space {
+            blocks: total_bytes / BLOCK_SIZE,
+            free_blocks: total_bytes.saturating_sub(used.bytes) / BLOCK_SIZE,
+            files: total_files,
+            free_files: total_files.saturating_sub(used.files),
+        })
+    }
+
+    /// Check that a table and the database have room for a file to grow by `growth` bytes, and count it
+    pub fn grow(&mut self, client: &mut Client, statements: &mut Statements, tables: &[&str], table: &TableQuota, growth: u64) -> Result<(), i32> {
+        if growth == 0 {
+            return Ok(());
+        }
+        if let Some(quota) = table.bytes {
+            let usage = self.table_usage(client, statements, table).map_err(|e| {
+                log::warn!("Unable to check quota of {}: {}", table.table_name, e);
+                EDQUOT
+            })?;
+            if usage.bytes + growth > quota {
+                return Err(EDQUOT);
+            }
+        }
+        if let Some(capacity) = self.capacity {
+            let usage = self.database_usage(client, statements, tables).map_err(|e| {
+                log::warn!("Unable to check database size: {}", e);
+                ENOSPC
+            })?;
+            if usage.bytes + growth > capacity {
+                return Err(ENOSPC);
+            }
+        }
+        self.count(table.table_name, growth as i64, 0);
+        Ok(())
+    }
+
+    /// Count the space freed by truncating a file
+    pub fn shrink(&mut self, table_name: &str, bytes: u64) {
+        self.count(table_name, -(bytes as i64), 0);
+    }
+
+    /// Check that a table has room for another file, which is counted once it has been added
+    pub fn check_new_file(&mut self, client: &mut Client, statements: &mut Statements, table: &TableQuota) -> Result<(), i32> {
+        if let Some(quota) = table.files {
+            let usage = self.table_usage(client, statements, table).map_err(|e| {
+                log::warn!("Unable to check quota of {}: {}", table.table_name, e);
+                EDQUOT
+            })?;
+            if usage.files >= quota {
+                return Err(EDQUOT);
+            }
+        }
+        Ok(())
+    }
+
+    /// Count a file added to a table, or restored from its trash
+    pub fn file_added(&mut self, table_name: &str, bytes: u64) {
+        self.count(table_name, bytes as i64, 1);
+    }
+
+    /// Count a file deleted from a table, or moved to its trash
+    pub fn file_removed(&mut self, table_name: &str, bytes: u64) {
+        self.count(table_name, -(bytes as i64), -1);
+    }
+
+    fn count(&mut self, table_name: &str, bytes: i64, files: i64) {
+        for key in [None, Some(table_name.to_string())] {
+            if let Some((_, usage)) = self.usage.get_mut(&key) {
+                usage.bytes = usage.bytes.saturating_add_signed(bytes);
+                usage.files = usage.files.saturating_add_signed(files);
+            }
+        }
+    }
+}
+
+/// Usage from a row of bytes and files, which aggregate queries always return
+fn usage_of(row: Option<postgres::Row>) -> Usage {
+    row.map_or(Usage { bytes: 0, files: 0 }, |row| Usage { bytes: row.get::<usize, i64>(0) as u64, files: row.get::<usize, i64>(1) as u64 })
+}
+
+/// A size in bytes, such as 1048576 or "1M"
+pub fn parse_size(value: &toml::Value) -> Option<u64> {
+    if let Some(bytes) = value.as_integer() {
+        return u64::try_from(bytes).ok();
+    }
+    let value = value.as_str()?.trim();
+    let (number, multiplier) = match value.char_indices().last()? {
+        (i, 'K' | 'k') => (&value[..i], 1 << 10),
+        (i, 'M' | 'm') => (&value[..i], 1 << 20),
+        (i, 'G' | 'g') => (&value[..i], 1 << 30),
+        (i, 'T' | 't') => (&value[..i], 1 << 40),
+        _ => (value, 1),
+    };
+    number.trim().parse::<u64>().ok().map(|number| number * multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toml::Value;
+
+    #[test]
+    fn parse_size_in_bytes() {
+        assert_eq!(parse_size(&Value::Integer(1048576)), Some(1048576));
+        assert_eq!(parse_size(&Value::String("512".into())), Some(512));
+    }
+
+    #[test]
+    fn parse_size_with_suffix() {
+        assert_eq!(parse_size(&Value::String("4K".into())), Some(4 << 10));
+        assert_eq!(parse_size(&Value::String("100M".into())), Some(100 << 20));
+        assert_eq!(parse_size(&Value::String("50g".into())), Some(50 << 30));
+        assert_eq!(parse_size(&Value::String(" 2 T ".into())), Some(2 << 40));
+    }
+
+    #[test]
+    fn parse_size_rejects_nonsense() {
+        assert_eq!(parse_size(&Value::String("".into())), None);
+        assert_eq!(parse_size(&Value::String("M".into())), None);
+        assert_eq!(parse_size(&Value::String("1.5G".into())), None);
+        assert_eq!(parse_size(&Value::String("10X".into())), None);
+        assert_eq!(parse_size(&Value::Integer(-1)), None);
+        assert_eq!(parse_size(&Value::Boolean(true)), None);
+    }
+
+    #[test]
+    fn count_never_goes_below_zero() {
+        let mut quotas = Quotas::new(None);
+        quotas.usage.insert(Some("files".to_string()), (Instant::now(), Usage { bytes: 100, files: 1 }));
+        quotas.file_removed("files", 150);
+        quotas.file_removed("files", 0);
+        let (_, usage) = quotas.usage[&Some("files".to_string())];
+        assert_eq!((usage.bytes, usage.files), (0, 0));
+        quotas.file_added("files", 10);
+        quotas.shrink("files", 4);
+        let (_, usage) = quotas.usage[&Some("files".to_string())];
+        assert_eq!((usage.bytes, usage.files), (6, 1));
+    }
+}
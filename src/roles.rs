@@ -0,0 +1,69 @@
+//! Mapping local users to PostgreSQL roles.
+//!
+//! With a `[roles]` section, requests are made as the PostgreSQL role mapped to the uid of the calling
+//! process, so GRANTs and row-level security decide what each local user can see and change. Keys are
+//! uids and values role names, and `default` gives the role for uids which aren't listed:
+//!
+//! ```toml
+//! [roles]
+//! 1000 = "alice"
+//! 1001 = "bob"
+//! default = "pgfs_guest"
+//! ```
+//!
+//! Without a `default`, unlisted uids (including root, which the kernel uses for some requests of its own)
+//! act as the connection user from `[database]`, which must be a member of every mapped role. The mount is
+//! made with `allow_other` so that other users can reach it, which needs `user_allow_other` in
+//! `/etc/fuse.conf` unless mounting as root.
+//!
+//! The main connection switches role with `SET ROLE` at the start of each request. A file's write
+//! transaction keeps the role of the user who opened it, set with `SET LOCAL ROLE` so that it ends with
+//! the transaction and the connection goes back to the pool as the connection user.
+//!
+//! The files listed for one user may include rows another user's role can't see, so each lookup and stat
+//! of a file checks its row again as the caller's role, and the kernel isn't allowed to keep entries or
+//! attributes for files.
+
+use libc::EACCES;
+use postgres::Client;
+use postgres::error::SqlState;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, Default)]
+pub struct Roles {
+    pub by_uid: HashMap<u32, String>,
+    pub default: Option<String>,
+}
+
+impl Roles {
+    /// The role for a uid, or None for the connection user
+    pub fn role_for(&self, uid: u32) -> Option<&str> {
+        self.by_uid.get(&uid).or(self.default.as_ref()).map(|role| role.as_str())
+    }
+}
+
+/// Switch the role of a connection for the rest of the session, or back to the connection user
+pub fn set_role(client: &mut Client, role: Option<&str>) -> Result<(), postgres::Error> {
+    match role {
+        Some(role) => client.batch_execute(&format!("SET ROLE {}", identifier(role))),
+        None => client.batch_execute("RESET ROLE"),
+    }
+}
+
+/// Statement switching role until the end of the current transaction
+pub fn set_local_role(role: &str) -> String {
+    format!("SET LOCAL ROLE {}", identifier(role))
+}
+
+/// The error to give for a failed statement: EACCES if the role isn't allowed to do it (by a GRANT or a
+/// row-level security policy), otherwise `other`
+pub fn errno(e: &postgres::Error, other: i32) -> i32 {
+    match e.code() {
+        Some(code) if *code == SqlState::INSUFFICIENT_PRIVILEGE => EACCES,
+        _ => other,
+    }
+}
+
+fn identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
@@ -0,0 +1,36 @@
+//! Point-in-time snapshot mounts.
+//!
+//! A snapshot mount shows every table as it was at one moment, so a backup or `rsync` of the mount sees a
+//! consistent copy even while the database changes underneath. The main connection stays in a single
+//! `REPEATABLE READ` transaction for the life of the mount, which either exports a new snapshot (its id is
+//! printed so that other tools, such as `pg_dump --snapshot`, can share it) or imports one exported
+//! elsewhere. The mount is read only.
+//!
+//! A failed statement aborts the transaction, so the next request after one starts by rolling back to a
+//! savepoint taken when the snapshot was set up.
+
+use postgres::Client;
+
+const SAVEPOINT: &str = "pgfs_snapshot";
+
+/// Start the snapshot transaction on `client`, importing the snapshot `id` if given or exporting a new
+/// one. Returns the snapshot id.
+pub fn start(client: &mut Client, id: Option<&str>) -> Result<String, postgres::Error> {
+    client.batch_execute("BEGIN ISOLATION LEVEL REPEATABLE READ READ ONLY")?;
+    let id = match id {
+        Some(id) => {
+            client.batch_execute(&format!("SET TRANSACTION SNAPSHOT '{}'", id.replace('\'', "''")))?;
+            id.to_string()
+        }
+        None => client.query_one("select pg_export_snapshot()", &[])?.get(0),
+    };
+    client.batch_execute(&format!("SAVEPOINT {}", SAVEPOINT))?;
+    Ok(id)
+}
+
+/// Recover the snapshot transaction after a statement failed in it
+pub fn reset(client: &mut Client) {
+    if let Err(e) = client.batch_execute(&format!("ROLLBACK TO SAVEPOINT {}", SAVEPOINT)) {
+        log::error!("Unable to reset snapshot transaction: {}", e);
+    }
+}
@@ -0,0 +1,232 @@
+//! Whole-file write-back.
+//!
+//! Writing a file through `overlay()` costs a query per flushed range, and each of those rewrites the
+//! whole value, so writing a large file is quadratic. Tables whose files are always rewritten whole can
+//! set `write_strategy = "whole_file"` instead: a file opened for writing is staged locally, and the last
+//! handle writing it to close (or an fsync) writes it back with a single UPDATE of the data column.
+//!
+//! The current content is only fetched when a write or truncate first needs it, so opening with
+//! `O_TRUNC` never reads the old content. Staged files are kept in memory up to `stage_in_memory` bytes
+//! and in an unlinked temp file in `stage_dir` (the system temp directory by default) beyond that. Spilled
+//! files are streamed to the server with COPY into a temporary table, so they don't have to fit in memory
+//! here.
+//!
+//! ```toml
+//! [write_cache]
+//! stage_in_memory = "8M"
+//! stage_dir = "/var/tmp"
+//!
+//! [documents]
+//! write_strategy = "whole_file"
+//! ```
+
+use postgres::Client;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Bytes fetched or sent at a time when moving content to or from the database
+const CHUNK_SIZE: usize = 1 << 20;
+
+static NEXT_SPILL: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Debug)]
+pub struct StageConfig {
+    ///most bytes of a file to keep in memory
+    pub memory: u64,
+    pub dir: Option<PathBuf>,
+}
+
+impl Default for StageConfig {
+    fn default() -> Self {
+        StageConfig { memory: 8 << 20, dir: None }
+    }
+}
+
+enum Content {
+    ///not fetched yet - reads still go to the database
+    Unloaded,
+    Memory(Vec<u8>),
+    Spilled(File, u64),
+}
+
+/// The content of a file being written whole
+pub struct Staged {
+    content: Content,
+    ///changed since it was last written back
+    pub changed: bool,
+}
+
+impl Default for Staged {
+    fn default() -> Self {
+        Staged { content: Content::Unloaded, changed: false }
+    }
+}
+
+impl Staged {
+    pub fn loaded(&self) -> bool {
+        !matches!(self.content, Content::Unloaded)
+    }
+
+    pub fn len(&self) -> u64 {
+        match &self.content {
+            Content::Unloaded => 0,
+            Content::Memory(bytes) => bytes.len() as u64,
+            Content::Spilled(_, len) => *len,
+        }
+    }
+
+    /// Fill in the first `size` bytes of the content (or all of it) with `fetch(offset, length)`, which
+    /// reads from the database, unless it has been loaded already
+    pub fn load(&mut self, config: &StageConfig, size: Option<u64>,
+                mut fetch: impl FnMut(u64, u64) -> Result<Vec<u8>, postgres::Error>) -> Result<(), Box<dyn std::error::Error>> {
+        if self.loaded() {
+            return Ok(());
+        }
+        self.content = Content::Memory(vec![]);
+        let result = self.fetch_into(config, size, &mut fetch);
+        if result.is_err() {
+            self.content = Content::Unloaded;
+        }
+        result
+    }
+
+    fn fetch_into(&mut self, config: &StageConfig, size: Option<u64>,
+                  fetch: &mut impl FnMut(u64, u64) -> Result<Vec<u8>, postgres::Error>) -> Result<(), Box<dyn std::error::Error>> {
+        while size.is_none_or(|size| self.len() < size) {
+            let length = size.map_or(CHUNK_SIZE as u64, |size| (size - self.len()).min(CHUNK_SIZE as u64));
+            let chunk = fetch(self.len(), length)?;
+            if chunk.is_empty() {
+                break;
+            }
+            let offset = self.len();
+            self.write(config, offset, &chunk)?;
+        }
+        //loading isn't a change
+        self.changed = false;
+        Ok(())
+    }
+
+    pub fn write(&mut self, config: &StageConfig, offset: u64, data: &[u8]) -> io::Result<()> {
+        let end = offset + data.len() as u64;
+        if let Content::Memory(bytes) = &self.content
+            && end > config.memory {
+            let mut file = spill_file(config)?;
+            file.write_all(bytes)?;
+            self.content = Content::Spilled(file, bytes.len() as u64);
+        }
+        match &mut self.content {
+            Content::Unloaded => {
+                self.content = Content::Memory(vec![]);
+                return self.write(config, offset, data);
+            }
+            Content::Memory(bytes) => {
+                if bytes.len() < end as usize {
+                    bytes.resize(end as usize, 0);
+                }
+                bytes[offset as usize..end as usize].copy_from_slice(data);
+            }
+            Content::Spilled(file, len) => {
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(data)?;
+                *len = (*len).max(end);
+            }
+        }
+        self.changed = true;
+        Ok(())
+    }
+
+    pub fn read(&mut self, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let end = self.len().min(offset + size as u64);
+        if offset >= end {
+            return Ok(vec![]);
+        }
+        match &mut self.content {
+            Content::Unloaded => Ok(vec![]),
+            Content::Memory(bytes) => Ok(bytes[offset as usize..end as usize].to_vec()),
+            Content::Spilled(file, _) => {
+                let mut data = vec![0; (end - offset) as usize];
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(&mut data)?;
+                Ok(data)
+            }
+        }
+    }
+
+    pub fn set_len(&mut self, config: &StageConfig, size: u64) -> io::Result<()> {
+        if size > config.memory && !matches!(self.content, Content::Spilled(..)) {
+            //extending past what is kept in memory; the file's new bytes read as zeros without being written
+            let mut file = spill_file(config)?;
+            if let Content::Memory(bytes) = &self.content {
+                file.write_all(bytes)?;
+            }
+            self.content = Content::Spilled(file, 0);
+        }
+        match &mut self.content {
+            Content::Unloaded => self.content = Content::Memory(vec![0; size as usize]),
+            Content::Memory(bytes) => bytes.resize(size as usize, 0),
+            Content::Spilled(file, len) => {
+                file.set_len(size)?;
+                *len = size;
+            }
+        }
+        self.changed = true;
+        Ok(())
+    }
+
+    /// The content, if it is small enough to have been kept in memory
+    pub fn in_memory(&self) -> Option<&[u8]> {
+        match &self.content {
+            Content::Memory(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Copy spilled content into the session's `pg_temp.pgfs_staging` table, for an UPDATE to take it from
+    pub fn copy_to_server(&mut self, client: &mut Client) -> Result<(), Box<dyn std::error::Error>> {
+        let Content::Spilled(file, len) = &mut self.content else {
+            return Ok(());
+        };
+        client.batch_execute("CREATE TEMPORARY TABLE IF NOT EXISTS pgfs_staging (data bytea); TRUNCATE pg_temp.pgfs_staging")?;
+        let mut writer = client.copy_in("COPY pg_temp.pgfs_staging (data) FROM STDIN")?;
+        //the text format, with the value as hex
+        writer.write_all(b"\\\\x")?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let mut remaining = *len;
+        while remaining > 0 {
+            let length = remaining.min(CHUNK_SIZE as u64) as usize;
+            file.read_exact(&mut chunk[..length])?;
+            writer.write_all(hex(&chunk[..length]).as_bytes())?;
+            remaining -= length as u64;
+        }
+        writer.write_all(b"\n")?;
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+/// Empty the temporary table once an UPDATE has taken spilled content from it
+pub fn clear_server(client: &mut Client) -> Result<(), postgres::Error> {
+    client.batch_execute("TRUNCATE pg_temp.pgfs_staging")
+}
+
+/// A temp file which is already unlinked, so it goes away however the process ends
+fn spill_file(config: &StageConfig) -> io::Result<File> {
+    let dir = config.dir.clone().unwrap_or_else(std::env::temp_dir);
+    let path = dir.join(format!("pgfs-{}-{}", std::process::id(), NEXT_SPILL.fetch_add(1, Ordering::Relaxed)));
+    let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+    fs::remove_file(&path)?;
+    Ok(file)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        hex.push(DIGITS[(byte >> 4) as usize] as char);
+        hex.push(DIGITS[(byte & 0xf) as usize] as char);
+    }
+    hex
+}
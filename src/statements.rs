@@ -0,0 +1,135 @@
+//! Prepared statements, and a mode for transaction pooling.
+//!
+//! The statements each callback builds for a table (reading a block, updating a row, listing a page) are
+//! prepared the first time they are run on a connection and kept, keyed by their SQL, so they are only
+//! parsed and planned once rather than on every call. The main connection keeps them for as long as the
+//! filesystem is mounted, and a file's write transaction for as long as the transaction.
+//!
+//! Behind a pooler in transaction mode, such as PgBouncer with `pool_mode = transaction`, one statement can
+//! run on a different server connection from the next, so named prepared statements can't be used at all.
+//! With `pgbouncer_transaction_mode = true` at the top level of the config, nothing is prepared and every
+//! statement is sent with the unnamed statement protocol, its parameter types taken from the values
+//! given for them. Features which need a session of their own (`[roles]`, `[snapshot]`, file locks,
+//! whole file writes spilled to disk, and the `[change_feed]` and `[channels]` listeners) still need a direct connection, so
+//! the mount refuses to start with them in this mode. File locks are on unless `file_locks = false`.
+//! Write transactions set their lock timeout with `SET LOCAL`, and the main connection asks for it in the
+//! `options` startup parameter, which the pooler has to pass on to the server.
+
+use bytes::BytesMut;
+use postgres::error::SqlState;
+use postgres::types::{ToSql, Type};
+use postgres::{Client, Error, Row, Statement};
+use std::collections::HashMap;
+
+/// Most statements to keep for a connection. The cache is emptied when it is full, which only happens
+/// when many `@<timestamp>` directories are visited, as each has its own queries.
+pub const CACHE_SIZE: usize = 256;
+
+/// Types tried, in order, for each parameter of a statement sent unprepared
+const PARAMETER_TYPES: [Type; 12] = [Type::INT4, Type::INT8, Type::INT2, Type::BYTEA, Type::TEXT, Type::BOOL,
+    Type::TIMESTAMPTZ, Type::FLOAT8, Type::FLOAT4, Type::JSONB, Type::TEXT_ARRAY, Type::INT4_ARRAY];
+
+/// Statements prepared on a connection, or none if statements are sent unprepared on it
+pub struct Statements {
+    prepared: HashMap<String, Statement>,
+    ///send every statement unprepared, for running behind a pooler in transaction mode
+    unnamed: bool,
+    ///a statement has failed since the last `take_failed`, which may have aborted the connection's transaction
+    failed: bool,
+}
+
+impl Statements {
+    pub fn new(unnamed: bool) -> Statements {
+        Statements { prepared: HashMap::new(), unnamed, failed: false }
+    }
+
+    /// Whether statements are sent unprepared
+    pub fn unnamed(&self) -> bool {
+        self.unnamed
+    }
+
+    /// Note a failure of a statement run on the connection some other way
+    pub fn mark_failed(&mut self) {
+        self.failed = true;
+    }
+
+    /// Whether any statement has failed since this was last called
+    pub fn take_failed(&mut self) -> bool {
+        std::mem::take(&mut self.failed)
+    }
+
+    pub fn query(&mut self, client: &mut Client, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Vec<Row>, Error> {
+        self.run(client, sql, |client, statement| client.query(statement, params), |client| query_unnamed(client, sql, params).map(|(rows, _)| rows))
+    }
+
+    /// The first row a query returns, if it returns any
+    pub fn query_opt(&mut self, client: &mut Client, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Option<Row>, Error> {
+        Ok(self.query(client, sql, params)?.into_iter().next())
+    }
+
+    pub fn execute(&mut self, client: &mut Client, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<u64, Error> {
+        self.run(client, sql, |client, statement| client.execute(statement, params), |client| query_unnamed(client, sql, params).map(|(_, affected)| affected))
+    }
+
+    fn run<T>(&mut self, client: &mut Client, sql: &str, prepared: impl Fn(&mut Client, &Statement) -> Result<T, Error>,
+              unprepared: impl FnOnce(&mut Client) -> Result<T, Error>) -> Result<T, Error> {
+        let result = self.try_run(client, sql, prepared, unprepared);
+        self.failed |= result.is_err();
+        result
+    }
+
+    /// Run a statement prepared, preparing it if it hasn't been, or unprepared in transaction pool mode
+    fn try_run<T>(&mut self, client: &mut Client, sql: &str, prepared: impl Fn(&mut Client, &Statement) -> Result<T, Error>,
+                  unprepared: impl FnOnce(&mut Client) -> Result<T, Error>) -> Result<T, Error> {
+        if self.unnamed {
+            return unprepared(client);
+        }
+        let statement = self.prepare(client, sql)?;
+        match prepared(client, &statement) {
+            //the statement's result columns have changed (the table was altered, say), so prepare it again
+            Err(e) if e.code() == Some(&SqlState::FEATURE_NOT_SUPPORTED) => {
+                self.prepared.remove(sql);
+                let statement = self.prepare(client, sql)?;
+                prepared(client, &statement)
+            }
+            result => result,
+        }
+    }
+
+    fn prepare(&mut self, client: &mut Client, sql: &str) -> Result<Statement, Error> {
+        if let Some(statement) = self.prepared.get(sql) {
+            return Ok(statement.clone());
+        }
+        if self.prepared.len() >= CACHE_SIZE {
+            self.prepared.clear();
+        }
+        let statement = client.prepare(sql)?;
+        self.prepared.insert(sql.to_string(), statement.clone());
+        Ok(statement)
+    }
+}
+
+/// Run a statement with the unnamed statement protocol, returning its rows and how many rows it affected
+fn query_unnamed(client: &mut Client, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<(Vec<Row>, u64), Error> {
+    use postgres::fallible_iterator::FallibleIterator;
+    let typed: Vec<(&(dyn ToSql + Sync), Type)> = params.iter().map(|param| (*param, parameter_type(*param))).collect();
+    let mut rows = client.query_typed_raw(sql, typed)?;
+    let mut result = vec![];
+    while let Some(row) = rows.next()? {
+        result.push(row);
+    }
+    let affected = rows.rows_affected().unwrap_or(result.len() as u64);
+    Ok((result, affected))
+}
+
+/// The first type a value can be sent as. The server converts it to whatever the statement needs, as it
+/// would a literal of that type.
+pub fn parameter_type(param: &(dyn ToSql + Sync)) -> Type {
+    let mut scratch = BytesMut::new();
+    PARAMETER_TYPES.into_iter()
+        .find(|ty| {
+            scratch.clear();
+            param.to_sql_checked(ty, &mut scratch).is_ok()
+        })
+        .unwrap_or(Type::UNKNOWN)
+}
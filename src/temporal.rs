@@ -0,0 +1,69 @@
+//! Time travel over tables which keep system-period history.
+//!
+//! A table with `valid_from_field` and `valid_to_field` set keeps old versions of its rows, each valid from
+//! one time until the next, either in the table itself or (temporal_tables style) in a separate
+//! `temporal_history_table` with the same columns. Such tables can be browsed as they were at any moment:
+//! for the whole mount with `--as-of=<timestamp>` on the command line, or through `@<timestamp>`
+//! directories at the top level, such as `/mnt/pgfs/@2026-01-01/files`. Either way they are read only.
+//! Only the last [`AS_OF_DIRS`] `@<timestamp>` directories looked up are kept; older ones are looked up
+//! again when next used, once the kernel stops caching their old inodes.
+//!
+//! The queries for a table are made to see it as of a time by defining a CTE with the table's own name,
+//! which holds the rows valid at that time and hides the real table from the rest of the query, so the
+//! configured `data_query` works unchanged. A CTE's name can't have a schema, so the `table_name` of a
+//! temporal table mustn't be schema qualified; put its schema in the `search_path` instead.
+
+use crate::statements::Statements;
+use postgres::Client;
+
+/// Most `@<timestamp>` directories to keep. Looking up another forgets the one looked up least recently.
+pub const AS_OF_DIRS: usize = 16;
+
+#[derive(Clone, Debug)]
+pub struct Temporal {
+    pub valid_from_field: String,
+    pub valid_to_field: String,
+    pub history_table: Option<String>,
+    ///the table's queries before they were limited to current rows
+    pub query_string: String,
+    pub data_query_string: String,
+    pub trash_query_string: Option<String>,
+}
+
+impl Temporal {
+    /// Rows of the table (and its history table) which were valid at a time, given as an SQL expression
+    fn rows_as_of(&self, table_name: &str, time: &str, with_history: bool) -> String {
+        let condition = format!("({from} is null or {from} <= {time}) and ({to} is null or {to} > {time})",
+            from = self.valid_from_field, to = self.valid_to_field);
+        match self.history_table.as_ref().filter(|_| with_history) {
+            Some(history_table) => format!("select * from {table_name} where {condition} \
+                union all select * from {history_table} where {condition}"),
+            None => format!("select * from {table_name} where {condition}"),
+        }
+    }
+
+    /// Make a query on the table see it as it was at a time
+    pub fn as_of_query(&self, query: &str, table_name: &str, timestamp: &str) -> String {
+        wrap(query, table_name, &self.rows_as_of(table_name, &literal(timestamp), true))
+    }
+
+    /// Make a query on the table see only the current version of each row, which matters for tables
+    /// keeping old versions alongside current ones
+    pub fn current_query(&self, query: &str, table_name: &str) -> String {
+        wrap(query, table_name, &self.rows_as_of(table_name, "now()", false))
+    }
+}
+
+/// Check that the database understands a timestamp, before using it in queries
+pub fn check_timestamp(client: &mut Client, statements: &mut Statements, timestamp: &str) -> Result<(), postgres::Error> {
+    statements.execute(client, "select $1::text::timestamptz", &[&timestamp])?;
+    Ok(())
+}
+
+fn wrap(query: &str, table_name: &str, rows: &str) -> String {
+    format!("with {} as ({}) select * from ({}) as pgfs_rows", table_name, rows, query.trim_end().trim_end_matches(';'))
+}
+
+fn literal(timestamp: &str) -> String {
+    format!("'{}'::timestamptz", timestamp.replace('\'', "''"))
+}
@@ -0,0 +1,148 @@
+//! Per-file write transactions.
+//!
+//! For tables with `write_transactions` set, opening a file for writing starts a transaction on a
+//! connection of its own, and every change to the file goes through that connection until the last handle
+//! writing the file is released, when the transaction is committed. Other readers see the file as it was
+//! until then, and if any write fails the whole transaction is rolled back rather than leaving the file
+//! partly written. `fsync` commits what has been written so far and starts a new transaction.
+//!
+//! Any failed statement aborts the transaction, and PostgreSQL then answers `COMMIT` with a rollback
+//! rather than an error, so each commit is sent after a `SELECT 1` which fails if the transaction has
+//! been aborted. Committing one which has is reported as a rollback.
+
+use crate::roles;
+use crate::statements::Statements;
+use postgres::Client;
+use postgres::error::SqlState;
+use std::collections::HashSet;
+
+/// How long a statement waits for a row locked by another transaction before failing. The filesystem
+/// is single threaded, so waiting forever on a lock held by one of its own transactions would hang it.
+pub const LOCK_TIMEOUT: &str = "5s";
+
+pub struct FileTransaction {
+    pub client: Client,
+    ///statements prepared on the connection during the transaction
+    pub statements: Statements,
+    ///handles open for writing which share this transaction
+    pub handles: HashSet<u64>,
+    ///a write has failed, so the transaction can only be rolled back
+    pub failed: bool,
+    ///the role of the user writing the file, when roles are mapped
+    role: Option<String>,
+}
+
+impl FileTransaction {
+    pub fn begin(client: Client, statements: Statements, fh: u64, role: Option<String>) -> Result<FileTransaction, postgres::Error> {
+        let mut transaction = FileTransaction {
+            client,
+            statements,
+            handles: HashSet::from([fh]),
+            failed: false,
+            role,
+        };
+        let begin = transaction.begin_statement();
+        transaction.client.batch_execute(&begin)?;
+        Ok(transaction)
+    }
+
+    /// Start a transaction. Its settings are made with `SET LOCAL`, so they don't outlast it on a
+    /// connection shared through a pooler in transaction mode.
+    fn begin_statement(&self) -> String {
+        let begin = format!("BEGIN; SET LOCAL lock_timeout = '{}'", LOCK_TIMEOUT);
+        match &self.role {
+            Some(role) => format!("{}; {}", begin, roles::set_local_role(role)),
+            None => begin,
+        }
+    }
+
+    /// Commit what has been written so far and carry on in a new transaction. Returns whether the changes
+    /// were committed; if not, the transaction is left failed for `finish` to roll back.
+    pub fn checkpoint(&mut self) -> Result<bool, postgres::Error> {
+        if self.failed {
+            return Ok(false);
+        }
+        let begin = self.begin_statement();
+        self.commit(&format!("COMMIT; {}", begin))
+    }
+
+    /// Commit, or roll back if a write failed. Returns whether the changes were committed.
+    pub fn finish(&mut self) -> Result<bool, postgres::Error> {
+        if self.failed {
+            self.client.batch_execute("ROLLBACK")?;
+            return Ok(false);
+        }
+        if !self.commit("COMMIT")? {
+            self.client.batch_execute("ROLLBACK")?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Run `commit` unless the transaction has been aborted by a failed statement, in which case it is
+    /// marked failed
+    fn commit(&mut self, commit: &str) -> Result<bool, postgres::Error> {
+        match self.client.batch_execute(&format!("SELECT 1; {}", commit)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.code() == Some(&SqlState::IN_FAILED_SQL_TRANSACTION) => {
+                self.failed = true;
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postgres::NoTls;
+
+    /// A connection with a temporary table to write to, if `PGFS_TEST_DATABASE` gives a connection string
+    /// (such as `host=localhost user=postgres dbname=pgfstest`). The tests are skipped without one.
+    fn connect() -> Option<Client> {
+        let config = std::env::var("PGFS_TEST_DATABASE").ok()?;
+        let mut client = Client::connect(&config, NoTls).expect("Unable to connect to PGFS_TEST_DATABASE");
+        client.batch_execute("CREATE TEMPORARY TABLE pgfs_test_writes (n int)").unwrap();
+        Some(client)
+    }
+
+    fn rows(transaction: &mut FileTransaction) -> i64 {
+        transaction.client.query_one("select count(*) from pg_temp.pgfs_test_writes", &[]).unwrap().get(0)
+    }
+
+    #[test]
+    fn finish_commits_writes() {
+        let Some(client) = connect() else { return };
+        let mut transaction = FileTransaction::begin(client, Statements::new(false), 1, None).unwrap();
+        transaction.client.batch_execute("insert into pg_temp.pgfs_test_writes values (1)").unwrap();
+        assert!(transaction.finish().unwrap());
+        assert_eq!(rows(&mut transaction), 1);
+    }
+
+    #[test]
+    fn finish_after_a_failed_statement_rolls_back() {
+        let Some(client) = connect() else { return };
+        let mut transaction = FileTransaction::begin(client, Statements::new(false), 1, None).unwrap();
+        transaction.client.batch_execute("insert into pg_temp.pgfs_test_writes values (1)").unwrap();
+        //nothing marked the transaction failed, but the server has aborted it
+        assert!(transaction.client.batch_execute("select 1/0").is_err());
+        assert!(!transaction.finish().unwrap());
+        assert!(transaction.failed);
+        assert_eq!(rows(&mut transaction), 0);
+    }
+
+    #[test]
+    fn checkpoint_after_a_failed_statement_leaves_the_transaction_failed() {
+        let Some(client) = connect() else { return };
+        let mut transaction = FileTransaction::begin(client, Statements::new(false), 1, None).unwrap();
+        transaction.client.batch_execute("insert into pg_temp.pgfs_test_writes values (1)").unwrap();
+        assert!(transaction.checkpoint().unwrap());
+        transaction.client.batch_execute("insert into pg_temp.pgfs_test_writes values (2)").unwrap();
+        assert!(transaction.client.batch_execute("select 1/0").is_err());
+        assert!(!transaction.checkpoint().unwrap());
+        assert!(transaction.failed);
+        assert!(!transaction.finish().unwrap());
+        assert_eq!(rows(&mut transaction), 1);
+    }
+}
@@ -0,0 +1,34 @@
+//! Trash directory for soft deleted files.
+//!
+//! For tables with a `deleted_field`, deleting a file sets that column (to `deleted_value`, by default
+//! `now()`) rather than deleting the row, and rows where it is anything other than `live_value` (by
+//! default null) are left out of the table's directory. They show up instead in the table's read-only
+//! `.trash` directory. Moving a file out of `.trash` into the table's directory restores it by setting
+//! the column back to `live_value`, and deleting a file in `.trash` deletes the row for good.
+
+use crate::Table;
+use crate::statements::Statements;
+use postgres::Client;
+
+pub const TRASH_DIR: &str = ".trash";
+
+/// Wrap the query listing a table's files so that it only returns rows which are deleted, or only those
+/// which aren't
+pub fn rows_query(data_query: &str, table_name: &str, id_field: &str, deleted_field: &str, live_value: &str, deleted: bool) -> String {
+    format!("select * from ({}) as pgfs_rows where id in (select {id} from {} where {} is {}distinct from {})",
+        data_query.trim_end().trim_end_matches(';'), table_name, deleted_field, if deleted { "" } else { "not " }, live_value,
+        id = id_field)
+}
+
+pub fn soft_delete(client: &mut Client, statements: &mut Statements, table: &Table, id: i32) -> Result<u64, postgres::Error> {
+    let deleted_field = table.deleted_field.as_deref().unwrap_or_default();
+    let query = format!("update {} set {} = {} where {} = $1", table.table_name, deleted_field, table.deleted_value, table.id_field);
+    statements.execute(client, query.as_str(), &[&id])
+}
+
+/// Bring a row back from the trash under a (possibly new) name
+pub fn restore(client: &mut Client, statements: &mut Statements, table: &Table, id: i32, name: &str) -> Result<u64, postgres::Error> {
+    let deleted_field = table.deleted_field.as_deref().unwrap_or_default();
+    let query = format!("update {} set {} = {}, {} = $2 where {} = $1", table.table_name, deleted_field, table.live_value, table.name_column(), table.id_field);
+    statements.execute(client, query.as_str(), &[&id, &name])
+}
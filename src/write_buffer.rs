@@ -0,0 +1,248 @@
+//! Buffering of writes.
+//!
+//! Writes are kept in memory as a map of dirty ranges for each file, with overlapping and adjacent writes
+//! merged (later writes winning), so tools which seek about as they write don't cost a query per write.
+//! A file's ranges are written out with one `overlay()` each when it is flushed, synced, released or read
+//! (so reads always see earlier writes), when they reach the table's `write_cache` limit, and when the
+//! buffers of all files together pass the global `size`, oldest first. Buffers which have been waiting
+//! `flush_after` seconds are written out too, but only when the next write to any file comes along, so
+//! there is no timer: a buffer left alone stays in memory until one of the others happens.
+//!
+//! ```toml
+//! [write_cache]
+//! size = "64M"
+//! file_size = "2M"
+//! flush_after = 5
+//!
+//! [torrents]
+//! write_cache = "16M"
+//! ```
+//!
+//! Writes bigger than a file's limit go straight to the database. A `write_cache` of 0 turns buffering
+//! off for a table. A failure writing out a file's buffer outside a flush, fsync or close of that file is
+//! kept, failing its reads and writes, until its next flush, fsync or close reports it.
+
+use crate::Inode;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct BufferConfig {
+    ///most bytes to buffer across all files
+    pub size: u64,
+    ///most bytes to buffer for one file, for tables which don't set `write_cache`
+    pub file_size: u64,
+    pub flush_after: Duration,
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        BufferConfig { size: 64 << 20, file_size: 2 << 20, flush_after: Duration::from_secs(5) }
+    }
+}
+
+/// Unwritten ranges of a file, by offset
+#[derive(Debug)]
+pub struct DirtyRanges {
+    ranges: BTreeMap<u64, Vec<u8>>,
+    bytes: u64,
+    since: Instant,
+}
+
+impl DirtyRanges {
+    fn new() -> DirtyRanges {
+        DirtyRanges { ranges: BTreeMap::new(), bytes: 0, since: Instant::now() }
+    }
+
+    fn insert(&mut self, offset: u64, data: &[u8]) {
+        let end = offset + data.len() as u64;
+        //ranges which overlap or touch the new one: perhaps one starting before it, then any starting in it
+        let mut touching: Vec<u64> = self.ranges.range(..offset).next_back()
+            .filter(|(start, range)| *start + range.len() as u64 >= offset)
+            .map(|(start, _)| *start)
+            .into_iter()
+            .collect();
+        touching.extend(self.ranges.range(offset..=end).map(|(start, _)| *start));
+        let start = touching.first().map_or(offset, |first| (*first).min(offset));
+        let mut merged_end = end;
+        let mut old = Vec::with_capacity(touching.len());
+        for key in touching {
+            let range = self.ranges.remove(&key).unwrap();
+            self.bytes -= range.len() as u64;
+            merged_end = merged_end.max(key + range.len() as u64);
+            old.push((key, range));
+        }
+        let mut merged = vec![0; (merged_end - start) as usize];
+        for (key, range) in old {
+            let from = (key - start) as usize;
+            merged[from..from + range.len()].copy_from_slice(&range);
+        }
+        let from = (offset - start) as usize;
+        merged[from..from + data.len()].copy_from_slice(data);
+        self.bytes += merged.len() as u64;
+        self.ranges.insert(start, merged);
+    }
+
+    /// The ranges in order of offset
+    pub fn into_ranges(self) -> impl Iterator<Item = (u64, Vec<u8>)> {
+        self.ranges.into_iter()
+    }
+}
+
+pub struct WriteBuffers {
+    config: BufferConfig,
+    files: HashMap<Inode, DirtyRanges>,
+    bytes: u64,
+}
+
+impl WriteBuffers {
+    pub fn new(config: BufferConfig) -> WriteBuffers {
+        WriteBuffers { config, files: HashMap::new(), bytes: 0 }
+    }
+
+    /// The most a file may buffer, given its table's limit if it has one
+    pub fn file_limit(&self, table_limit: Option<u64>) -> u64 {
+        table_limit.unwrap_or(self.config.file_size)
+    }
+
+    /// Whether a file's buffer has room for `length` more bytes
+    pub fn fits(&self, ino: Inode, length: usize, limit: u64) -> bool {
+        self.files.get(&ino).map_or(0, |file| file.bytes) + length as u64 <= limit
+    }
+
+    pub fn write(&mut self, ino: Inode, offset: u64, data: &[u8]) {
+        let file = self.files.entry(ino).or_insert_with(DirtyRanges::new);
+        self.bytes -= file.bytes;
+        file.insert(offset, data);
+        self.bytes += file.bytes;
+    }
+
+    pub fn contains(&self, ino: Inode) -> bool {
+        self.files.contains_key(&ino)
+    }
+
+    /// Take a file's buffered ranges, to write them out
+    pub fn take(&mut self, ino: Inode) -> Option<DirtyRanges> {
+        let file = self.files.remove(&ino)?;
+        self.bytes -= file.bytes;
+        Some(file)
+    }
+
+    /// Files which should be written out now: those which have waited too long, then the oldest until the
+    /// rest fit in the global limit
+    pub fn due(&self) -> Vec<Inode> {
+        let mut files: Vec<(&Inode, &DirtyRanges)> = self.files.iter().collect();
+        files.sort_by_key(|(_, file)| file.since);
+        let mut bytes = self.bytes;
+        files.into_iter()
+            .take_while(|(_, file)| {
+                let due = bytes > self.config.size || file.since.elapsed() >= self.config.flush_after;
+                bytes -= file.bytes;
+                due
+            })
+            .map(|(ino, _)| *ino)
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.files.clear();
+        self.bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(file: DirtyRanges) -> Vec<(u64, Vec<u8>)> {
+        file.into_ranges().collect()
+    }
+
+    #[test]
+    fn separate_writes_stay_separate() {
+        let mut file = DirtyRanges::new();
+        file.insert(10, b"cd");
+        file.insert(0, b"ab");
+        file.insert(20, b"ef");
+        assert_eq!(file.bytes, 6);
+        assert_eq!(ranges(file), vec![(0, b"ab".to_vec()), (10, b"cd".to_vec()), (20, b"ef".to_vec())]);
+    }
+
+    #[test]
+    fn adjacent_writes_are_merged() {
+        let mut file = DirtyRanges::new();
+        file.insert(0, b"ab");
+        file.insert(2, b"cd");
+        file.insert(6, b"gh");
+        file.insert(4, b"ef");
+        assert_eq!(file.bytes, 8);
+        assert_eq!(ranges(file), vec![(0, b"abcdefgh".to_vec())]);
+    }
+
+    #[test]
+    fn overlapping_writes_are_merged_with_later_ones_winning() {
+        let mut file = DirtyRanges::new();
+        file.insert(2, b"cdef");
+        file.insert(0, b"ABC");
+        file.insert(5, b"FGH");
+        assert_eq!(file.bytes, 8);
+        assert_eq!(ranges(file), vec![(0, b"ABCdeFGH".to_vec())]);
+    }
+
+    #[test]
+    fn a_write_can_bridge_and_cover_ranges() {
+        let mut file = DirtyRanges::new();
+        file.insert(0, b"ab");
+        file.insert(4, b"ef");
+        file.insert(8, b"ij");
+        file.insert(1, b"XYZW");
+        assert_eq!(ranges(file), vec![(0, b"aXYZWf".to_vec()), (8, b"ij".to_vec())]);
+        let mut file = DirtyRanges::new();
+        file.insert(2, b"cd");
+        file.insert(6, b"gh");
+        file.insert(0, b"ABCDEFGHIJ");
+        assert_eq!(file.bytes, 10);
+        assert_eq!(ranges(file), vec![(0, b"ABCDEFGHIJ".to_vec())]);
+    }
+
+    #[test]
+    fn buffers_count_their_bytes() {
+        let mut buffers = WriteBuffers::new(BufferConfig { size: 100, file_size: 10, flush_after: Duration::from_secs(60) });
+        buffers.write(2, 0, b"abcd");
+        buffers.write(2, 2, b"cdef");
+        buffers.write(3, 0, b"xy");
+        assert_eq!(buffers.bytes, 8);
+        assert!(buffers.fits(2, 4, buffers.file_limit(None)));
+        assert!(!buffers.fits(2, 5, buffers.file_limit(None)));
+        assert!(buffers.fits(2, 10, buffers.file_limit(Some(20))));
+        assert_eq!(buffers.take(2).map(ranges), Some(vec![(0, b"abcdef".to_vec())]));
+        assert_eq!(buffers.bytes, 2);
+        assert!(buffers.take(2).is_none());
+    }
+
+    #[test]
+    fn oldest_files_are_due_past_the_global_size() {
+        let mut buffers = WriteBuffers::new(BufferConfig { size: 10, file_size: 10, flush_after: Duration::from_secs(60) });
+        //writes in quick succession can get the same Instant, so age them by hand
+        let now = Instant::now();
+        for (ino, age) in [(2, 4), (3, 3), (4, 2), (5, 1)] {
+            buffers.files.insert(ino, DirtyRanges { since: now - Duration::from_secs(age), ..DirtyRanges::new() });
+        }
+        buffers.write(2, 0, &[0; 4]);
+        buffers.write(3, 0, &[0; 4]);
+        assert!(buffers.due().is_empty());
+        buffers.write(4, 0, &[0; 4]);
+        assert_eq!(buffers.due(), vec![2]);
+        buffers.write(5, 0, &[0; 6]);
+        assert_eq!(buffers.due(), vec![2, 3]);
+        buffers.clear();
+        assert!(buffers.due().is_empty());
+    }
+
+    #[test]
+    fn files_are_due_after_waiting() {
+        let mut buffers = WriteBuffers::new(BufferConfig { size: 100, file_size: 10, flush_after: Duration::ZERO });
+        buffers.write(2, 0, b"ab");
+        assert_eq!(buffers.due(), vec![2]);
+    }
+}
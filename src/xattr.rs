@@ -0,0 +1,113 @@
+//! Extended attributes from row columns.
+//!
+//! The columns listed in a table's `xattr_fields` show up on each of its files as `user.pgfs.<column>`
+//! attributes, holding the column's value as text, so tools can get at row metadata (a picture's
+//! `mime_type`, say) with `getfattr` rather than a query. Setting one updates the column, converting the
+//! text to the column's type, and removing one sets the column to null. GRANTs decide who may change
+//! them.
+//!
+//! With an `xattr_column` (a jsonb column), any other `user.` attribute can be set too, and is kept in
+//! that column as a string under its name without the `user.` prefix. Their values must be UTF-8.
+//!
+//! ```toml
+//! xattr_fields = ["mime_type", "description"]
+//! xattr_column = "xattrs"
+//! ```
+
+use crate::statements::Statements;
+use postgres::Client;
+
+pub const FIELD_PREFIX: &str = "user.pgfs.";
+const USER_PREFIX: &str = "user.";
+
+/// Where an attribute is kept
+#[derive(Debug)]
+pub enum Attribute {
+    ///a column of its own
+    Field(String),
+    ///a key in the xattr column
+    Stored(String),
+}
+
+/// The columns a table keeps attributes in
+pub struct Source<'a> {
+    pub table_name: &'a str,
+    pub id_field: &'a str,
+    pub fields: &'a [String],
+    pub column: Option<&'a str>,
+}
+
+impl Source<'_> {
+    /// Where the attribute with a name is kept, if the table has anywhere for it
+    pub fn attribute(&self, name: &str) -> Option<Attribute> {
+        //user.pgfs.* names are kept for columns, even ones which aren't exposed
+        if let Some(field) = name.strip_prefix(FIELD_PREFIX) {
+            return self.fields.iter().any(|f| f == field).then(|| Attribute::Field(field.to_string()));
+        }
+        let key = name.strip_prefix(USER_PREFIX)?;
+        self.column.map(|_| Attribute::Stored(key.to_string()))
+    }
+
+    /// The value of an attribute of a row, or None if it isn't set. `scope` adjusts the query to the
+    /// table's view of its rows (as of a time, for instance). Errors with None if the row has gone.
+    pub fn get(&self, client: &mut Client, statements: &mut Statements, scope: impl Fn(&str) -> String, id: i32, attribute: &Attribute) -> Result<Option<String>, Option<postgres::Error>> {
+        let query = match attribute {
+            Attribute::Field(field) => format!("select {}::text from {} where {} = $1", field, self.table_name, self.id_field),
+            Attribute::Stored(key) => format!("select {} ->> {} from {} where {} = $1",
+                self.column.unwrap_or_default(), literal(key), self.table_name, self.id_field),
+        };
+        let row = statements.query_opt(client, scope(&query).as_str(), &[&id]).map_err(Some)?.ok_or(None)?;
+        Ok(row.get(0))
+    }
+
+    /// Names of the attributes a row has set
+    pub fn list(&self, client: &mut Client, statements: &mut Statements, scope: impl Fn(&str) -> String, id: i32) -> Result<Vec<String>, Option<postgres::Error>> {
+        let mut columns: Vec<String> = self.fields.iter().map(|field| format!("{} is not null", field)).collect();
+        if let Some(column) = self.column {
+            columns.push(format!("(select array_agg(k) from jsonb_object_keys(coalesce({}, '{{}}')) as k)", column));
+        }
+        if columns.is_empty() {
+            return Ok(vec![]);
+        }
+        let query = format!("select {} from {} where {} = $1", columns.join(", "), self.table_name, self.id_field);
+        let row = statements.query_opt(client, scope(&query).as_str(), &[&id]).map_err(Some)?.ok_or(None)?;
+        let mut names: Vec<String> = self.fields.iter().enumerate()
+            .filter(|(i, _)| row.get::<usize, bool>(*i))
+            .map(|(_, field)| format!("{}{}", FIELD_PREFIX, field))
+            .collect();
+        if self.column.is_some() {
+            let keys: Option<Vec<String>> = row.get(self.fields.len());
+            names.extend(keys.unwrap_or_default().into_iter().map(|key| format!("{}{}", USER_PREFIX, key)));
+        }
+        Ok(names)
+    }
+
+    /// The SET clause giving an attribute the value in `$1`, or removing it if `value` is false
+    pub fn assignment(&self, attribute: &Attribute, value: bool) -> String {
+        match (attribute, value) {
+            //let the row type convert the text to whatever type the column is
+            (Attribute::Field(field), true) => format!("{field} = (jsonb_populate_record(null::{}, jsonb_build_object('{field}', $1::text))).{field}",
+                self.table_name),
+            (Attribute::Field(field), false) => format!("{} = null", field),
+            (Attribute::Stored(key), true) => format!("{0} = jsonb_set(coalesce({0}, '{{}}'), array[{1}], to_jsonb($1::text))",
+                self.column.unwrap_or_default(), literal(key)),
+            (Attribute::Stored(key), false) => format!("{0} = {0} - {1}", self.column.unwrap_or_default(), literal(key)),
+        }
+    }
+}
+
+fn literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Reply to a get or list request of `size` bytes with `data`, as the protocol asks: the size needed if
+/// `size` is 0, otherwise the data if it fits
+pub fn reply(reply: fuser::ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
+    }
+}